@@ -0,0 +1,360 @@
+use anyhow::{bail, Context};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader, Lines},
+    process::{Child, ChildStdout, Command},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::capabilities::Capabilities;
+use crate::probe::{native, parse_timestamp, Probe};
+
+#[cfg(feature = "fake")]
+pub mod fake;
+#[cfg(feature = "libav")]
+pub mod libav;
+
+/// How many lines of ffmpegs stderr get kept around to explain a failure
+const STDERR_TAIL_LINES: usize = 12;
+
+/// Where probing and encoding actually happen. [`Ffmpeg`] runs the ffmpeg and ffprobe
+/// binaries, `libav::Libav` links their libraries with the `libav` feature, and
+/// `fake::Fake` only pretends to, for tests with the `fake` feature.
+pub trait Backend: Send + Sync + 'static {
+    type Pass: Pass;
+
+    /// Reads the streams and format of `path`
+    fn probe(&self, path: &Path) -> impl Future<Output = anyhow::Result<Probe>> + Send;
+
+    /// Timestamps of all keyframes in the first video stream, in seconds
+    fn keyframes(&self, path: &Path) -> impl Future<Output = anyhow::Result<Vec<f32>>> + Send;
+
+    /// Starts one ffmpeg run with `args`, see [`crate::plan::Job::args`]
+    fn start(&self, args: &[String]) -> anyhow::Result<Self::Pass>;
+}
+
+/// One running encode. Dropping it before it exited kills it.
+pub trait Pass: Send {
+    /// Waits for the next progress report, `None` once the encode stopped reporting
+    fn progress(&mut self) -> impl Future<Output = Option<Progress>> + Send;
+
+    /// Waits for the encode to exit
+    fn wait(self) -> impl Future<Output = anyhow::Result<Exit>> + Send;
+}
+
+/// Where a running encode is at
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Progress {
+    pub out_time: f32, //secs
+    pub speed: Option<f32>,
+    pub size: u64, //bytes
+}
+
+/// How an encode exited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exit {
+    /// `None` if it was killed by a signal
+    pub code: Option<i32>,
+    /// Last lines it wrote to stderr
+    pub stderr_tail: Vec<String>,
+}
+
+impl Exit {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// An encode running in-process on a blocking thread, reporting its progress over a channel
+pub struct BlockingPass {
+    progress: mpsc::UnboundedReceiver<Progress>,
+    encode: JoinHandle<anyhow::Result<()>>,
+    cancelled: Arc<AtomicBool>,
+    failed: fn(anyhow::Error) -> anyhow::Result<Exit>,
+}
+
+impl BlockingPass {
+    /// Runs `encode` with where to report progress and whether it's been cancelled. `failed`
+    /// turns the error it returns into an [`Exit`], or passes it on.
+    pub fn spawn<F>(encode: F, failed: fn(anyhow::Error) -> anyhow::Result<Exit>) -> Self
+    where
+        F: FnOnce(&mpsc::UnboundedSender<Progress>, &AtomicBool) -> anyhow::Result<()>,
+        F: Send + 'static,
+    {
+        let (sender, progress) = mpsc::unbounded_channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let encode = tokio::task::spawn_blocking({
+            let cancelled = cancelled.clone();
+            move || encode(&sender, &cancelled)
+        });
+        BlockingPass {
+            progress,
+            encode,
+            cancelled,
+            failed,
+        }
+    }
+}
+
+impl Pass for BlockingPass {
+    async fn progress(&mut self) -> Option<Progress> {
+        self.progress.recv().await
+    }
+
+    async fn wait(mut self) -> anyhow::Result<Exit> {
+        match (&mut self.encode).await.context("Encode panicked")? {
+            Ok(()) => Ok(Exit {
+                code: Some(0),
+                stderr_tail: vec![],
+            }),
+            Err(e) => (self.failed)(e),
+        }
+    }
+}
+
+impl Drop for BlockingPass {
+    /// The encode runs on a blocking thread, which can't be aborted, it checks this instead
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The ffmpeg and ffprobe binaries, by default whichever are on `PATH`
+#[derive(Debug, Clone)]
+pub struct Ffmpeg {
+    ffmpeg: PathBuf,
+    ffprobe: PathBuf,
+}
+
+impl Default for Ffmpeg {
+    fn default() -> Self {
+        Ffmpeg::new("ffmpeg".into(), "ffprobe".into())
+    }
+}
+
+impl Ffmpeg {
+    pub fn new(ffmpeg: PathBuf, ffprobe: PathBuf) -> Self {
+        Ffmpeg { ffmpeg, ffprobe }
+    }
+
+    /// The ffmpeg invocation running `args`, with progress written to stdout in ffmpegs
+    /// `-progress` format
+    pub fn command(&self, args: &[String]) -> Command {
+        let mut command = Command::new(&self.ffmpeg);
+        command.args(["-progress", "-", "-nostats", "-stats_period", "50ms"]);
+        command.args(args);
+        command
+    }
+
+    /// Asks ffmpeg what it can do
+    pub async fn capabilities(&self) -> anyhow::Result<Capabilities> {
+        Ok(Capabilities::parse(
+            &run(&self.ffmpeg, &["-version"]).await?,
+            &run(&self.ffmpeg, &["-hide_banner", "-encoders"]).await?,
+            &run(&self.ffmpeg, &["-hide_banner", "-filters"]).await?,
+        ))
+    }
+}
+
+impl Ffmpeg {
+    /// Whether ffprobe runs, without it files get read with [`native::probe`]
+    pub async fn has_ffprobe(&self) -> bool {
+        run(&self.ffprobe, &["-version"]).await.is_ok()
+    }
+}
+
+/// [`native::probe`] off the async threads, for when ffprobe isn't around or makes no sense
+async fn native_probe(path: &Path) -> anyhow::Result<Probe> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || native::probe(&path)).await?
+}
+
+/// Stdout of `program` with `args`, which must succeed
+async fn run(program: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .with_context(|| {
+            format!(
+                "Can't run `{}`, install it or point --ffmpeg/--ffprobe at it",
+                program.display()
+            )
+        })?;
+    if !output.status.success() {
+        bail!(
+            "`{} {}` failed with {}",
+            program.display(),
+            args.join(" "),
+            output.status
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+impl Backend for Ffmpeg {
+    type Pass = FfmpegPass;
+
+    async fn probe(&self, path: &Path) -> anyhow::Result<Probe> {
+        let ffprobe = Command::new(&self.ffprobe)
+            .args([
+                "-v",
+                "error",
+                "-of",
+                "json",
+                "-show_streams",
+                "-show_format",
+            ])
+            .arg(path)
+            .stderr(Stdio::piped())
+            .output()
+            .await;
+        let ffprobe = match ffprobe {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return native_probe(path).await,
+            ffprobe => ffprobe.context("Failed to run ffprobe. Make sure ffprobe is installed")?,
+        };
+        if !ffprobe.status.success() {
+            bail!(
+                "ffprobe can't read it: {}",
+                String::from_utf8_lossy(&ffprobe.stderr).trim()
+            );
+        }
+        let mut probe = match serde_json::from_slice::<Probe>(&ffprobe.stdout) {
+            Ok(probe) => probe,
+            Err(e) => {
+                return native_probe(path)
+                    .await
+                    .map_err(|_| e)
+                    .context("Failed to understand ffprobes output")
+            }
+        };
+        if probe.has_gaps() {
+            if let Ok(native) = native_probe(path).await {
+                probe.fill_gaps(native);
+            }
+        }
+        Ok(probe)
+    }
+
+    async fn keyframes(&self, path: &Path) -> anyhow::Result<Vec<f32>> {
+        let ffprobe = Command::new(&self.ffprobe)
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v:0",
+                "-skip_frame",
+                "nokey",
+                "-show_entries",
+                "frame=pts_time",
+                "-of",
+                "csv=p=0",
+            ])
+            .arg(path)
+            .stderr(Stdio::piped())
+            .output()
+            .await?;
+        if !ffprobe.status.success() {
+            bail!("Failed to read keyframes with ffprobe, {}", ffprobe.status);
+        }
+
+        Ok(std::str::from_utf8(&ffprobe.stdout)?
+            .lines()
+            .filter_map(|l| l.trim().trim_end_matches(',').parse::<f32>().ok())
+            .collect())
+    }
+
+    fn start(&self, args: &[String]) -> anyhow::Result<FfmpegPass> {
+        let mut command = self.command(args);
+        command.stdout(Stdio::piped());
+        command.stderr(Stdio::piped());
+        command.stdin(Stdio::null());
+        // a cancelled job must not leave ffmpeg running
+        command.kill_on_drop(true);
+        let mut child = command.spawn().context("Failed to start ffmpeg")?;
+        let stdout = child
+            .stdout
+            .take()
+            .context("encoder stdout missing - exited early or unavailable")?;
+        // drained in the background so ffmpeg never blocks on a full pipe
+        let stderr = child.stderr.take().map(stderr_tail);
+        Ok(FfmpegPass {
+            child,
+            lines: BufReader::new(stdout).lines(),
+            stderr,
+            progress: Progress::default(),
+        })
+    }
+}
+
+pub struct FfmpegPass {
+    child: Child,
+    /// ffmpegs `-progress -` output, one `key=value` per line
+    lines: Lines<BufReader<ChildStdout>>,
+    stderr: Option<JoinHandle<Vec<String>>>,
+    progress: Progress,
+}
+
+impl Pass for FfmpegPass {
+    async fn progress(&mut self) -> Option<Progress> {
+        while let Ok(Some(line)) = self.lines.next_line().await {
+            let progress = &mut self.progress;
+            if let Some(time) = line.strip_prefix("out_time=") {
+                progress.out_time = parse_timestamp(time).unwrap_or(progress.out_time);
+            } else if let Some(speed) = line.strip_prefix("speed=") {
+                progress.speed = speed.trim().trim_end_matches('x').parse().ok();
+            } else if let Some(size) = line.strip_prefix("total_size=") {
+                progress.size = size.trim().parse().unwrap_or(progress.size);
+            } else if line.starts_with("progress=") {
+                // every block of progress ends with this line
+                return Some(*progress);
+            }
+        }
+        None
+    }
+
+    async fn wait(mut self) -> anyhow::Result<Exit> {
+        // stdout closing doesn't mean the file is flushed yet, wait for ffmpeg to exit
+        let status = self
+            .child
+            .wait()
+            .await
+            .context("Failed to wait for ffmpeg")?;
+        let stderr_tail = match self.stderr {
+            Some(stderr) => stderr.await.unwrap_or_default(),
+            None => vec![],
+        };
+        Ok(Exit {
+            code: status.code(),
+            stderr_tail,
+        })
+    }
+}
+
+/// Reads `stderr` to the end, keeping its last [`STDERR_TAIL_LINES`] lines
+fn stderr_tail(stderr: impl AsyncRead + Unpin + Send + 'static) -> JoinHandle<Vec<String>> {
+    tokio::spawn(async move {
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        tail.into()
+    })
+}
@@ -0,0 +1,151 @@
+use anyhow::{bail, Context};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{Backend, Exit, Pass, Progress};
+use crate::probe::Probe;
+
+/// Scripted stand-in for ffmpeg and ffprobe, so planning and running jobs can be tested
+/// without either installed. Probes answer with what was given to [`Fake::with_probe`],
+/// encodes write zeros of the next scripted size to their output and report a few steps of
+/// progress. Outputs it wrote probe as a single video stream.
+pub struct Fake {
+    probes: HashMap<PathBuf, Probe>,
+    keyframes: Vec<f32>,
+    /// Size of each output written, the last one repeats
+    output_sizes: Mutex<VecDeque<u64>>,
+    failure: Option<(i32, Vec<String>)>,
+    hang: bool,
+    written: Mutex<HashSet<PathBuf>>,
+    runs: Mutex<Vec<Vec<String>>>,
+}
+
+impl Default for Fake {
+    fn default() -> Self {
+        Fake {
+            probes: HashMap::new(),
+            keyframes: vec![],
+            output_sizes: Mutex::new(VecDeque::from([1000])),
+            failure: None,
+            hang: false,
+            written: Mutex::new(HashSet::new()),
+            runs: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl Fake {
+    pub fn new() -> Self {
+        Fake::default()
+    }
+
+    /// What probing `path` returns
+    pub fn with_probe(mut self, path: impl Into<PathBuf>, probe: Probe) -> Self {
+        self.probes.insert(path.into(), probe);
+        self
+    }
+
+    pub fn with_keyframes(mut self, keyframes: Vec<f32>) -> Self {
+        self.keyframes = keyframes;
+        self
+    }
+
+    /// Sizes in bytes of the outputs encodes write, in order. The last one repeats.
+    pub fn with_output_sizes(self, sizes: impl IntoIterator<Item = u64>) -> Self {
+        *self.output_sizes.lock().unwrap() = sizes.into_iter().collect();
+        self
+    }
+
+    /// Every encode exits with `code` after writing `stderr`
+    pub fn failing(mut self, code: i32, stderr: &[&str]) -> Self {
+        self.failure = Some((code, stderr.iter().map(|l| l.to_string()).collect()));
+        self
+    }
+
+    /// Every encode writes its output, then never finishes
+    pub fn hanging(mut self) -> Self {
+        self.hang = true;
+        self
+    }
+
+    /// Arguments of every encode started so far
+    pub fn runs(&self) -> Vec<Vec<String>> {
+        self.runs.lock().unwrap().clone()
+    }
+
+    fn next_output_size(&self) -> u64 {
+        let mut sizes = self.output_sizes.lock().unwrap();
+        match sizes.len() {
+            0 => 0,
+            1 => sizes[0],
+            _ => sizes.pop_front().unwrap_or_default(),
+        }
+    }
+}
+
+impl Backend for Fake {
+    type Pass = FakePass;
+
+    async fn probe(&self, path: &Path) -> anyhow::Result<Probe> {
+        if let Some(probe) = self.probes.get(path) {
+            return Ok(probe.clone());
+        }
+        if self.written.lock().unwrap().contains(path) && path.exists() {
+            return Ok(serde_json::from_value(serde_json::json!({
+                "streams": [{ "index": 0, "codec_type": "video" }],
+                "format": {},
+            }))?);
+        }
+        bail!("ffprobe can't read it: {}: No such file", path.display())
+    }
+
+    async fn keyframes(&self, _path: &Path) -> anyhow::Result<Vec<f32>> {
+        Ok(self.keyframes.clone())
+    }
+
+    fn start(&self, args: &[String]) -> anyhow::Result<FakePass> {
+        self.runs.lock().unwrap().push(args.to_vec());
+        let output = args.last().context("No output")?;
+        // pass 1 of two-pass encodes only writes its log
+        if output != "/dev/null" && output != "NUL" {
+            let size = self.next_output_size();
+            std::fs::write(output, vec![0; size as usize])?;
+            self.written.lock().unwrap().insert(PathBuf::from(output));
+        }
+        let (code, stderr_tail) = self.failure.clone().unwrap_or((0, vec![]));
+        Ok(FakePass {
+            progress: (1..=3)
+                .map(|step| Progress {
+                    out_time: step as f32 * 0.5,
+                    speed: Some(1.),
+                    size: step * 100,
+                })
+                .collect(),
+            exit: Exit {
+                code: Some(code),
+                stderr_tail,
+            },
+            hang: self.hang,
+        })
+    }
+}
+
+pub struct FakePass {
+    progress: VecDeque<Progress>,
+    exit: Exit,
+    hang: bool,
+}
+
+impl Pass for FakePass {
+    async fn progress(&mut self) -> Option<Progress> {
+        if self.hang {
+            std::future::pending::<()>().await;
+        }
+        self.progress.pop_front()
+    }
+
+    async fn wait(self) -> anyhow::Result<Exit> {
+        Ok(self.exit)
+    }
+}
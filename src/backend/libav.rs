@@ -0,0 +1,1000 @@
+use anyhow::{anyhow, bail, Context as _};
+use ffmpeg_next as ffmpeg;
+use ffmpeg_next::{
+    codec, decoder, encoder, filter, format, media, Dictionary, Frame, Packet, Rational, Rescale,
+};
+use serde_json::{json, Map, Value};
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use super::{Backend, BlockingPass, Exit, Progress};
+use crate::capabilities::{Capabilities, FILTERS};
+use crate::probe::Probe;
+use crate::{AudioCodec, VideoCodec};
+
+/// Same period [`super::Ffmpeg`] asks the ffmpeg binary to report progress at
+const PROGRESS_PERIOD: Duration = Duration::from_millis(50);
+
+/// Probes and encodes in-process through the linked libavformat, libavcodec and
+/// libavfilter, instead of running the ffmpeg and ffprobe binaries. Encodes take the same
+/// arguments [`crate::plan::Job::args`] gives ffmpeg, of which the options nmb uses are
+/// understood, so both backends write the same outputs.
+pub struct Libav;
+
+impl Libav {
+    pub fn new() -> anyhow::Result<Self> {
+        ffmpeg::init().context("Failed to initialize libav")?;
+        // failures come back as errors, anything logged would end up between the progress bars
+        ffmpeg::log::set_level(ffmpeg::log::Level::Quiet);
+        Ok(Libav)
+    }
+
+    /// What the linked libraries can do, for the same checks the ffmpeg binary goes through
+    pub fn capabilities(&self) -> Capabilities {
+        let video = [
+            VideoCodec::WEBM,
+            VideoCodec::HEVC,
+            VideoCodec::AV1,
+            VideoCodec::AV1MP4,
+            VideoCodec::H264,
+        ]
+        .map(|c| c.profile().video_encoder());
+        let audio = [
+            AudioCodec::OPUS,
+            AudioCodec::OPUSNATIVE,
+            AudioCodec::VORBIS,
+            AudioCodec::AAC,
+        ]
+        .map(|a| a.encoder());
+        Capabilities::new(
+            video
+                .into_iter()
+                .chain(audio)
+                .chain(["libwebp"])
+                .filter(|e| encoder::find_by_name(e).is_some())
+                .map(str::to_owned),
+            FILTERS
+                .into_iter()
+                .filter(|f| filter::find(f).is_some())
+                .map(str::to_owned),
+        )
+    }
+}
+
+impl Backend for Libav {
+    type Pass = BlockingPass;
+
+    async fn probe(&self, path: &Path) -> anyhow::Result<Probe> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || probe(&path))
+            .await
+            .context("Probing panicked")?
+    }
+
+    async fn keyframes(&self, path: &Path) -> anyhow::Result<Vec<f32>> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || keyframes(&path))
+            .await
+            .context("Reading keyframes panicked")?
+    }
+
+    fn start(&self, args: &[String]) -> anyhow::Result<BlockingPass> {
+        let invocation = Invocation::parse(args)?;
+        Ok(BlockingPass::spawn(
+            move |progress, cancelled| transcode(&invocation, progress, cancelled),
+            // worded like ffmpeg's own errors, so failures read the same with either backend
+            |e| {
+                Ok(Exit {
+                    code: Some(1),
+                    stderr_tail: vec![format!("Error: {e:#}")],
+                })
+            },
+        ))
+    }
+}
+
+/// ffprobe's `-show_streams -show_format` json, rebuilt from what libavformat read
+fn probe(path: &Path) -> anyhow::Result<Probe> {
+    let input = format::input(&path).map_err(|e| anyhow!("libav can't read it: {e}"))?;
+    let streams = input.streams().map(|s| stream_json(&s)).collect::<Vec<_>>();
+    let probe = json!({
+        "streams": streams,
+        "format": {
+            "format_name": input.format().name(),
+            "duration": (input.duration() > 0)
+                .then(|| input.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE)),
+            "bit_rate": (input.bit_rate() > 0).then(|| input.bit_rate()),
+            "size": std::fs::metadata(path).ok().map(|m| m.len()),
+            "tags": tags(input.metadata()),
+        },
+    });
+    serde_json::from_value(probe).context("Failed to understand what libav read")
+}
+
+fn stream_json(stream: &ffmpeg::Stream) -> Value {
+    let parameters = stream.parameters();
+    let disposition = stream.disposition();
+    let mut json = json!({
+        "index": stream.index(),
+        "codec_type": match parameters.medium() {
+            media::Type::Video => "video",
+            media::Type::Audio => "audio",
+            media::Type::Subtitle => "subtitle",
+            _ => "data",
+        },
+        "codec_name": parameters.id().name(),
+        "r_frame_rate": rate(stream.rate()),
+        "avg_frame_rate": rate(stream.avg_frame_rate()),
+        "duration": (stream.duration() > 0)
+            .then(|| stream.duration() as f64 * f64::from(stream.time_base())),
+        "nb_frames": (stream.frames() > 0).then(|| stream.frames()),
+        "disposition": {
+            "default": u8::from(disposition.contains(format::stream::Disposition::DEFAULT)),
+            "attached_pic": u8::from(
+                disposition.contains(format::stream::Disposition::ATTACHED_PIC)
+            ),
+        },
+        "tags": tags(stream.metadata()),
+        "side_data_list": rotation(stream)
+            .map(|rotation| vec![json!({ "rotation": rotation })])
+            .unwrap_or_default(),
+    });
+    // size, pixel format and sample rate need a decoder, streams without one just lack them
+    let Ok(context) = codec::Context::from_parameters(parameters) else {
+        return json;
+    };
+    match context.medium() {
+        media::Type::Video => {
+            if let Ok(video) = context.decoder().video() {
+                json["width"] = video.width().into();
+                json["height"] = video.height().into();
+                json["pix_fmt"] = video.format().name().into();
+                json["bit_rate"] = nonzero(video.bit_rate());
+            }
+        }
+        media::Type::Audio => {
+            if let Ok(audio) = context.decoder().audio() {
+                json["sample_rate"] = audio.rate().into();
+                json["channels"] = audio.channels().into();
+                json["bit_rate"] = nonzero(audio.bit_rate());
+            }
+        }
+        _ => {}
+    }
+    json
+}
+
+/// `num/den`, the way ffprobe prints rates
+fn rate(rate: Rational) -> String {
+    format!("{}/{}", rate.numerator(), rate.denominator())
+}
+
+fn nonzero(value: usize) -> Value {
+    match value {
+        0 => Value::Null,
+        value => value.into(),
+    }
+}
+
+fn tags(metadata: ffmpeg::DictionaryRef) -> Map<String, Value> {
+    metadata
+        .iter()
+        .map(|(key, value)| (key.to_owned(), value.into()))
+        .collect()
+}
+
+/// Degrees of the display matrix, counterclockwise like ffprobe prints it
+fn rotation(stream: &ffmpeg::Stream) -> Option<f64> {
+    let matrix = stream
+        .side_data()
+        .find(|d| d.kind() == ffmpeg::packet::side_data::Type::DisplayMatrix)?;
+    if matrix.data().len() < 9 * size_of::<i32>() {
+        return None;
+    }
+    let rotation = unsafe { ffmpeg::ffi::av_display_rotation_get(matrix.data().as_ptr().cast()) };
+    (!rotation.is_nan()).then_some(rotation)
+}
+
+/// Timestamps of the keyframes in the first video stream, from its packets
+fn keyframes(path: &Path) -> anyhow::Result<Vec<f32>> {
+    let mut input = format::input(&path).context("Failed to read keyframes with libav")?;
+    let (index, time_base) = {
+        let stream = input
+            .streams()
+            .best(media::Type::Video)
+            .context("No video stream to read keyframes of")?;
+        (stream.index(), f64::from(stream.time_base()))
+    };
+    let mut keyframes = input
+        .packets()
+        .filter(|(stream, packet)| stream.index() == index && packet.is_key())
+        .filter_map(|(_, packet)| packet.pts())
+        .map(|pts| (pts as f64 * time_base) as f32)
+        .collect::<Vec<_>>();
+    // packets come in decode order
+    keyframes.sort_by(f32::total_cmp);
+    Ok(keyframes)
+}
+
+/// What an ffmpeg command line from [`crate::plan::Job::args`] asks for
+#[derive(Debug, Default, PartialEq)]
+struct Invocation {
+    input: PathBuf,
+    /// `-ss` before `-i`, secs
+    start: Option<f64>,
+    /// `-t`, secs
+    duration: Option<f64>,
+    /// `None` for `-f null`, where only the encoders run
+    output: Option<PathBuf>,
+    video: StreamArgs,
+    /// `None` for `-an`
+    audio: Option<StreamArgs>,
+    /// `-movflags`, `-loop`, for the muxer
+    muxer: Vec<(String, String)>,
+    pass: Option<u8>,
+    passlogfile: Option<PathBuf>,
+}
+
+/// Options of one output stream. Streams only get encoded if their encoder was named.
+#[derive(Debug, Default, PartialEq)]
+struct StreamArgs {
+    encoder: Option<String>,
+    filters: Option<String>,
+    pix_fmt: Option<String>,
+    /// `-tag:v`, the fourcc mp4 gets
+    tag: Option<String>,
+    qscale: Option<f32>,
+    /// Everything else, handed to the encoder as AVOptions like ffmpeg does
+    options: Vec<(String, String)>,
+}
+
+impl Invocation {
+    fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut invocation = Invocation::default();
+        let mut audio = StreamArgs::default();
+        let mut input = None;
+        let mut outputs = vec![];
+        let mut null = false;
+        let mut no_audio = false;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(option) = arg.strip_prefix('-').filter(|o| !o.is_empty()) else {
+                outputs.push(PathBuf::from(arg));
+                continue;
+            };
+            match option {
+                "y" | "nostats" => continue,
+                "an" => {
+                    no_audio = true;
+                    continue;
+                }
+                _ => {}
+            }
+            let value = args
+                .next()
+                .with_context(|| format!("{arg} is missing its value"))?
+                .clone();
+            let (name, stream) = match option.split_once(':') {
+                Some((name, "v")) => (name, Some(media::Type::Video)),
+                Some((name, "a")) => (name, Some(media::Type::Audio)),
+                Some(_) => bail!("libav backend doesn't understand {arg}"),
+                None => (option, None),
+            };
+            match (name, stream) {
+                ("i", None) => input = Some(PathBuf::from(value)),
+                ("ss", None) if input.is_none() => invocation.start = Some(seconds(&value)?),
+                ("t", None) => invocation.duration = Some(seconds(&value)?),
+                ("f", None) => null = value == "null",
+                ("pass", None) => invocation.pass = Some(value.parse()?),
+                ("passlogfile", None) => invocation.passlogfile = Some(value.into()),
+                ("movflags" | "loop", None) => invocation.muxer.push((name.to_owned(), value)),
+                // frames keep their timestamps here anyway
+                ("fps_mode", _) => {}
+                ("vcodec", None) | ("c", Some(media::Type::Video)) => {
+                    invocation.video.encoder = Some(value)
+                }
+                ("acodec", None) | ("c", Some(media::Type::Audio)) => audio.encoder = Some(value),
+                ("vf", None) | ("filter", Some(media::Type::Video)) => {
+                    invocation.video.filters = Some(value)
+                }
+                ("pix_fmt", None | Some(media::Type::Video)) => {
+                    invocation.video.pix_fmt = Some(value)
+                }
+                ("tag", Some(media::Type::Video)) => invocation.video.tag = Some(value),
+                ("qscale", None | Some(media::Type::Video)) => {
+                    invocation.video.qscale = Some(value.parse()?)
+                }
+                (name, Some(media::Type::Video)) => {
+                    invocation.video.options.push((name.to_owned(), value))
+                }
+                (name, Some(_)) => audio.options.push((name.to_owned(), value)),
+                // unqualified options go to every encoder, which ignore what isn't theirs
+                (name, None) => {
+                    invocation
+                        .video
+                        .options
+                        .push((name.to_owned(), value.clone()));
+                    audio.options.push((name.to_owned(), value));
+                }
+            }
+        }
+        invocation.input = input.context("No -i input")?;
+        let output = outputs.pop().context("No output")?;
+        invocation.output = (!null).then_some(output);
+        invocation.audio = (!no_audio).then_some(audio);
+        // libx264 keeps its own stats file, ffmpeg points it at the pass log
+        if let (Some(log), Some("libx264")) =
+            (invocation.passlog(), invocation.video.encoder.as_deref())
+        {
+            let log = log.to_string_lossy().into_owned();
+            invocation.video.options.push(("stats".into(), log));
+        }
+        Ok(invocation)
+    }
+
+    /// `<passlogfile>-0.log`, where ffmpeg keeps the stats of the first output stream
+    fn passlog(&self) -> Option<PathBuf> {
+        self.pass?;
+        let mut log = self.passlogfile.clone()?.into_os_string();
+        log.push("-0.log");
+        Some(log.into())
+    }
+}
+
+/// `12.5`, or `00:00:12.5` like ffmpeg also takes
+fn seconds(value: &str) -> anyhow::Result<f64> {
+    crate::probe::parse_timestamp(value)
+        .map(f64::from)
+        .with_context(|| format!("Bad duration {value}"))
+}
+
+/// Whether a `receive_*` call just has nothing more to give for now
+fn drained(error: &ffmpeg::Error) -> bool {
+    matches!(
+        error,
+        ffmpeg::Error::Eof
+            | ffmpeg::Error::Other {
+                errno: ffmpeg::error::EAGAIN
+            }
+    )
+}
+
+/// Runs one encode to the end, the way the ffmpeg binary would with the same arguments
+fn transcode(
+    invocation: &Invocation,
+    progress: &mpsc::UnboundedSender<Progress>,
+    cancelled: &AtomicBool,
+) -> anyhow::Result<()> {
+    let mut input = format::input(&invocation.input)
+        .map_err(|e| anyhow!("{}: {e}", invocation.input.display()))?;
+    let mut output = match &invocation.output {
+        Some(path) => Some(format::output(path).map_err(|e| anyhow!("{}: {e}", path.display()))?),
+        None => None,
+    };
+
+    // outputs start at 0, or at -ss, like ffmpeg does without -copyts
+    let start_time = match unsafe { (*input.as_ptr()).start_time } {
+        ffmpeg::ffi::AV_NOPTS_VALUE => 0.,
+        start_time => start_time as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE),
+    };
+    let origin = start_time + invocation.start.unwrap_or(0.);
+    if let Some(start) = invocation.start {
+        let target = (origin * f64::from(ffmpeg::ffi::AV_TIME_BASE)) as i64;
+        input
+            .seek(target, ..target)
+            .with_context(|| format!("Can't seek to {start}s"))?;
+    }
+
+    let mut transcoders = vec![];
+    if let Some(name) = &invocation.video.encoder {
+        let stream = input
+            .streams()
+            .best(media::Type::Video)
+            .context("Input has no video stream")?;
+        transcoders.push(Transcoder::video(
+            &stream,
+            name,
+            &invocation.video,
+            invocation,
+            output.as_mut(),
+            origin,
+        )?);
+    }
+    // pass 1 only writes its log, which is about the video
+    let audio = invocation.audio.as_ref().filter(|_| output.is_some());
+    if let Some((name, args)) = audio.and_then(|a| Some((a.encoder.as_ref()?, a))) {
+        if let Some(stream) = input.streams().best(media::Type::Audio) {
+            transcoders.push(Transcoder::audio(
+                &stream,
+                name,
+                args,
+                invocation,
+                output.as_mut(),
+                origin,
+            )?);
+        }
+    }
+    if transcoders.is_empty() {
+        bail!("Nothing to encode");
+    }
+
+    let mut muxer = Muxer {
+        output,
+        size: 0,
+        progress,
+        started: Instant::now(),
+        reported: None,
+        out_time: 0.,
+    };
+    if let Some(output) = &mut muxer.output {
+        let mut options = Dictionary::new();
+        for (key, value) in &invocation.muxer {
+            options.set(key, value);
+        }
+        output
+            .write_header_with(options)
+            .context("Can't write the output header")?;
+    }
+
+    for (stream, packet) in input.packets() {
+        if cancelled.load(Ordering::Relaxed) {
+            bail!("Cancelled");
+        }
+        let Some(transcoder) = transcoders
+            .iter_mut()
+            .find(|t| t.input == stream.index() && !t.done)
+        else {
+            continue;
+        };
+        transcoder.decode(&packet, &mut muxer)?;
+        if transcoders.iter().all(|t| t.done) {
+            break;
+        }
+    }
+    for transcoder in &mut transcoders {
+        transcoder.finish(&mut muxer)?;
+    }
+    if let Some(output) = &mut muxer.output {
+        output
+            .write_trailer()
+            .context("Can't finish writing the output")?;
+    }
+    if invocation.pass == Some(1) {
+        if let (Some(log), Some(video)) = (invocation.passlog(), transcoders.first()) {
+            video.write_stats(&log)?;
+        }
+    }
+    muxer.report(true);
+    Ok(())
+}
+
+/// Where encoded packets go, keeping track of how far the encode got
+struct Muxer<'a> {
+    /// `None` for `-f null`
+    output: Option<format::context::Output>,
+    size: u64, //bytes
+    progress: &'a mpsc::UnboundedSender<Progress>,
+    started: Instant,
+    reported: Option<Instant>,
+    out_time: f64, //secs
+}
+
+impl Muxer<'_> {
+    fn write(
+        &mut self,
+        packet: &mut Packet,
+        stream: usize,
+        time_base: Rational,
+    ) -> anyhow::Result<()> {
+        self.size += packet.size() as u64;
+        if let Some(pts) = packet.pts() {
+            self.out_time = self.out_time.max(pts as f64 * f64::from(time_base));
+        }
+        if let Some(output) = &mut self.output {
+            let stream_time_base = output
+                .stream(stream)
+                .context("Output stream went missing")?
+                .time_base();
+            packet.set_stream(stream);
+            packet.rescale_ts(time_base, stream_time_base);
+            packet
+                .write_interleaved(output)
+                .context("Can't write to the output")?;
+        }
+        self.report(false);
+        Ok(())
+    }
+
+    /// Sends progress every [`PROGRESS_PERIOD`], or now if it's the `last` report
+    fn report(&mut self, last: bool) {
+        if !last && self.reported.is_some_and(|r| r.elapsed() < PROGRESS_PERIOD) {
+            return;
+        }
+        self.reported = Some(Instant::now());
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let _ = self.progress.send(Progress {
+            out_time: self.out_time as f32,
+            speed: (elapsed > 0.).then(|| (self.out_time / elapsed) as f32),
+            size: self.size,
+        });
+    }
+}
+
+/// One input stream, decoded, filtered and encoded into one output stream
+struct Transcoder {
+    input: usize,
+    output: usize,
+    kind: media::Type,
+    decoder: decoder::Opened,
+    graph: filter::Graph,
+    encoder: encoder::Encoder,
+    /// Time base of the input stream, and its timestamp outputs start at
+    time_base: Rational,
+    origin: i64,
+    /// Time base of what goes into the filter graph
+    filter_time_base: Rational,
+    /// `-t`, secs
+    duration: Option<f64>,
+    /// Past `-t`, the rest of the stream isn't needed
+    done: bool,
+}
+
+impl Transcoder {
+    fn video(
+        stream: &ffmpeg::Stream,
+        name: &str,
+        args: &StreamArgs,
+        invocation: &Invocation,
+        output: Option<&mut format::context::Output>,
+        origin: f64,
+    ) -> anyhow::Result<Self> {
+        let codec = encoder::find_by_name(name).with_context(|| format!("No {name} encoder"))?;
+        let decoder = codec::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()
+            .context("Can't decode the video")?;
+
+        let pix_fmt = match &args.pix_fmt {
+            Some(pix_fmt) => format::Pixel::from_str(pix_fmt)
+                .map_err(|_| anyhow!("Unknown pixel format {pix_fmt}"))?,
+            None => match codec.video()?.formats().map(|f| f.collect::<Vec<_>>()) {
+                Some(formats) if !formats.is_empty() && !formats.contains(&decoder.format()) => {
+                    formats[0]
+                }
+                _ => decoder.format(),
+            },
+        };
+        // ffmpeg turns the picture upright before any other filter
+        let upright = rotation(stream).map_or(0, |r| (-r).round() as i32);
+        let mut filters = match upright.rem_euclid(360) {
+            90 => vec!["transpose=clock"],
+            180 => vec!["hflip", "vflip"],
+            270 => vec!["transpose=cclock"],
+            _ => vec![],
+        };
+        filters.extend(args.filters.as_deref());
+        let aspect = match decoder.aspect_ratio() {
+            aspect if aspect.numerator() > 0 => aspect,
+            _ => Rational::new(1, 1),
+        };
+        let mut buffer = format!(
+            "video_size={}x{}:pix_fmt={}:time_base={}:pixel_aspect={}",
+            decoder.width(),
+            decoder.height(),
+            decoder.format().name(),
+            rate(stream.time_base()),
+            rate(aspect),
+        );
+        if stream.avg_frame_rate().numerator() > 0 {
+            buffer += &format!(":frame_rate={}", rate(stream.avg_frame_rate()));
+        }
+        let spec = match filters.is_empty() {
+            true => "null".to_owned(),
+            false => filters.join(","),
+        };
+        let mut graph = filter_graph(("buffer", &buffer), "buffersink", &spec, |sink| {
+            sink.set_pixel_format(pix_fmt)
+        })?;
+        let mut sink = graph.get("out").context("Filter graph lost its output")?;
+        let time_base = sink.sink().time_base();
+        let (width, height, frame_rate, aspect) = unsafe {
+            let sink = sink.as_ptr();
+            (
+                ffmpeg::ffi::av_buffersink_get_w(sink),
+                ffmpeg::ffi::av_buffersink_get_h(sink),
+                Rational::from(ffmpeg::ffi::av_buffersink_get_frame_rate(sink)),
+                Rational::from(ffmpeg::ffi::av_buffersink_get_sample_aspect_ratio(sink)),
+            )
+        };
+
+        let mut context = codec::Context::new_with_codec(codec).encoder().video()?;
+        context.set_width(width as u32);
+        context.set_height(height as u32);
+        context.set_format(pix_fmt);
+        context.set_time_base(time_base);
+        if frame_rate.numerator() > 0 {
+            context.set_frame_rate(Some(frame_rate));
+        }
+        if aspect.numerator() > 0 {
+            context.set_aspect_ratio(aspect);
+        }
+        let mut flags = global_header(output.as_deref());
+        if let Some(qscale) = args.qscale {
+            flags |= codec::Flags::QSCALE;
+            context.set_global_quality((qscale * ffmpeg::ffi::FF_QP2LAMBDA as f32) as i32);
+        }
+        match (invocation.pass, invocation.passlog()) {
+            (Some(1), Some(_)) => flags |= codec::Flags::PASS1,
+            (Some(2), Some(log)) => {
+                flags |= codec::Flags::PASS2;
+                // libx264 reads its stats file itself
+                if name != "libx264" {
+                    let stats = std::fs::read(&log)
+                        .with_context(|| format!("Can't read pass log {}", log.display()))?;
+                    let stats = CString::new(stats).context("Pass log is corrupt")?;
+                    unsafe {
+                        (*context.as_mut_ptr()).stats_in = ffmpeg::ffi::av_strdup(stats.as_ptr());
+                    }
+                }
+            }
+            _ => {}
+        }
+        context.set_flags(flags);
+        let encoder = context
+            .open_with(options(&args.options))
+            .with_context(|| format!("Can't open the {name} encoder"))?
+            .0
+             .0;
+        let tag = args.tag.as_deref().map(fourcc).transpose()?;
+
+        Ok(Transcoder {
+            input: stream.index(),
+            output: add_stream(output, codec, &encoder, tag)?,
+            kind: media::Type::Video,
+            decoder: decoder.0,
+            graph,
+            encoder,
+            time_base: stream.time_base(),
+            origin: (origin / f64::from(stream.time_base())).round() as i64,
+            filter_time_base: stream.time_base(),
+            duration: invocation.duration,
+            done: false,
+        })
+    }
+
+    fn audio(
+        stream: &ffmpeg::Stream,
+        name: &str,
+        args: &StreamArgs,
+        invocation: &Invocation,
+        output: Option<&mut format::context::Output>,
+        origin: f64,
+    ) -> anyhow::Result<Self> {
+        let codec = encoder::find_by_name(name).with_context(|| format!("No {name} encoder"))?;
+        let decoder = codec::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .audio()
+            .context("Can't decode the audio")?;
+        let supported = codec.audio()?;
+
+        // what the encoder takes that's closest to the input, libavfilter converts to it
+        let layout = match decoder.channel_layout() {
+            layout if layout.bits() != 0 => layout,
+            _ => ffmpeg::ChannelLayout::default(i32::from(decoder.channels())),
+        };
+        let out_layout = supported
+            .channel_layouts()
+            .map_or(layout, |layouts| layouts.best(layout.channels()));
+        let in_rate = decoder.rate() as i32;
+        let out_rate = match supported.rates().map(|r| r.collect::<Vec<_>>()) {
+            Some(rates) if !rates.is_empty() && !rates.contains(&in_rate) => rates
+                .iter()
+                .copied()
+                .filter(|r| *r >= in_rate)
+                .min()
+                .or(rates.iter().copied().max())
+                .unwrap_or(in_rate),
+            _ => in_rate,
+        };
+        let sample_fmt = supported
+            .formats()
+            .and_then(|mut formats| formats.next())
+            .unwrap_or(decoder.format());
+
+        let buffer = format!(
+            "time_base=1/{in_rate}:sample_rate={in_rate}:sample_fmt={}:channel_layout=0x{:x}",
+            decoder.format().name(),
+            layout.bits()
+        );
+        let mut graph = filter_graph(("abuffer", &buffer), "abuffersink", "anull", |sink| {
+            sink.set_sample_format(sample_fmt);
+            sink.set_channel_layout(out_layout);
+            sink.set_sample_rate(out_rate as u32);
+        })?;
+
+        let mut context = codec::Context::new_with_codec(codec).encoder().audio()?;
+        context.set_rate(out_rate);
+        context.set_channel_layout(out_layout);
+        context.set_format(sample_fmt);
+        context.set_time_base(Rational::new(1, out_rate));
+        context.set_flags(global_header(output.as_deref()));
+        let encoder = context
+            .open_with(options(&args.options))
+            .with_context(|| format!("Can't open the {name} encoder"))?;
+        // most audio encoders take a fixed number of samples per frame
+        if !codec
+            .capabilities()
+            .contains(codec::Capabilities::VARIABLE_FRAME_SIZE)
+        {
+            graph
+                .get("out")
+                .context("Filter graph lost its output")?
+                .sink()
+                .set_frame_size(encoder.frame_size());
+        }
+        let encoder = encoder.0 .0;
+
+        Ok(Transcoder {
+            input: stream.index(),
+            output: add_stream(output, codec, &encoder, None)?,
+            kind: media::Type::Audio,
+            decoder: decoder.0,
+            graph,
+            encoder,
+            time_base: stream.time_base(),
+            origin: (origin / f64::from(stream.time_base())).round() as i64,
+            filter_time_base: Rational::new(1, in_rate),
+            duration: invocation.duration,
+            done: false,
+        })
+    }
+
+    fn decode(&mut self, packet: &Packet, muxer: &mut Muxer) -> anyhow::Result<()> {
+        // like ffmpeg, a broken packet only loses its frames
+        if self.decoder.send_packet(packet).is_err() {
+            return Ok(());
+        }
+        self.receive_frames(muxer)
+    }
+
+    /// Flushes everything still in the decoder, filters and encoder
+    fn finish(&mut self, muxer: &mut Muxer) -> anyhow::Result<()> {
+        self.decoder.send_eof().context("Can't decode the input")?;
+        self.receive_frames(muxer)?;
+        self.graph
+            .get("in")
+            .context("Filter graph lost its input")?
+            .source()
+            .flush()
+            .context("Can't filter the input")?;
+        self.filter(muxer)?;
+        self.encoder.send_eof().context("Can't encode")?;
+        self.receive_packets(muxer)
+    }
+
+    /// Decoded frames go into the filter graph, with their timestamps moved to the origin
+    fn receive_frames(&mut self, muxer: &mut Muxer) -> anyhow::Result<()> {
+        loop {
+            let mut frame = unsafe { Frame::empty() };
+            match self.decoder.receive_frame(&mut frame) {
+                Ok(()) => {}
+                Err(e) if drained(&e) => return Ok(()),
+                Err(e) => return Err(e).context("Can't decode the input"),
+            }
+            let Some(timestamp) = frame.timestamp().filter(|_| !self.done) else {
+                continue;
+            };
+            let pts = timestamp - self.origin;
+            if pts < 0 {
+                continue;
+            }
+            if self
+                .duration
+                .is_some_and(|d| pts as f64 * f64::from(self.time_base) >= d)
+            {
+                self.done = true;
+                continue;
+            }
+            frame.set_pts(Some(pts.rescale(self.time_base, self.filter_time_base)));
+            if self.kind == media::Type::Video {
+                // keyframes are up to the encoder, not wherever the input had them
+                unsafe {
+                    (*frame.as_mut_ptr()).pict_type =
+                        ffmpeg::ffi::AVPictureType::AV_PICTURE_TYPE_NONE;
+                }
+            }
+            self.graph
+                .get("in")
+                .context("Filter graph lost its input")?
+                .source()
+                .add(&frame)
+                .context("Can't filter the input")?;
+            self.filter(muxer)?;
+        }
+    }
+
+    fn filter(&mut self, muxer: &mut Muxer) -> anyhow::Result<()> {
+        loop {
+            let mut frame = unsafe { Frame::empty() };
+            let filtered = self
+                .graph
+                .get("out")
+                .context("Filter graph lost its output")?
+                .sink()
+                .frame(&mut frame);
+            match filtered {
+                Ok(()) => {}
+                Err(e) if drained(&e) => return Ok(()),
+                Err(e) => return Err(e).context("Can't filter the input"),
+            }
+            self.encoder.send_frame(&frame).context("Can't encode")?;
+            self.receive_packets(muxer)?;
+        }
+    }
+
+    fn receive_packets(&mut self, muxer: &mut Muxer) -> anyhow::Result<()> {
+        let time_base = self.encoder.time_base();
+        loop {
+            let mut packet = Packet::empty();
+            match self.encoder.receive_packet(&mut packet) {
+                Ok(()) => muxer.write(&mut packet, self.output, time_base)?,
+                Err(e) if drained(&e) => return Ok(()),
+                Err(e) => return Err(e).context("Can't encode"),
+            }
+        }
+    }
+
+    /// Writes what the encoder collected for pass 2, like ffmpeg does after pass 1
+    fn write_stats(&self, log: &Path) -> anyhow::Result<()> {
+        let stats = unsafe { (*self.encoder.as_ptr()).stats_out };
+        // libx264 wrote its own
+        if stats.is_null() {
+            return Ok(());
+        }
+        let stats = unsafe { CStr::from_ptr(stats) };
+        std::fs::write(log, stats.to_bytes())
+            .with_context(|| format!("Can't write pass log {}", log.display()))
+    }
+}
+
+/// `source -> spec -> sink`, named `in` and `out`. `configure` sets what the sink must
+/// put out.
+fn filter_graph(
+    (source, args): (&str, &str),
+    sink: &str,
+    spec: &str,
+    configure: impl FnOnce(&mut filter::Context),
+) -> anyhow::Result<filter::Graph> {
+    let find = |name: &str| filter::find(name).with_context(|| format!("No {name} filter"));
+    let mut graph = filter::Graph::new();
+    graph.add(&find(source)?, "in", args)?;
+    graph.add(&find(sink)?, "out", "")?;
+    configure(&mut graph.get("out").context("Filter graph lost its output")?);
+    graph
+        .output("in", 0)?
+        .input("out", 0)?
+        .parse(spec)
+        .with_context(|| format!("Bad filters {spec}"))?;
+    graph.validate().context("Can't set up the filters")?;
+    Ok(graph)
+}
+
+/// Some containers want codec headers up front rather than in the stream
+fn global_header(output: Option<&format::context::Output>) -> codec::Flags {
+    match output.is_some_and(|o| o.format().flags().contains(format::Flags::GLOBAL_HEADER)) {
+        true => codec::Flags::GLOBAL_HEADER,
+        false => codec::Flags::empty(),
+    }
+}
+
+/// Adds the stream `encoder` writes to, returning its index
+fn add_stream(
+    output: Option<&mut format::context::Output>,
+    codec: ffmpeg::Codec,
+    encoder: &encoder::Encoder,
+    tag: Option<u32>,
+) -> anyhow::Result<usize> {
+    // pass 1 has nowhere to write to
+    let Some(output) = output else {
+        return Ok(0);
+    };
+    let mut stream = output.add_stream(codec)?;
+    stream.set_parameters(encoder);
+    stream.set_time_base(encoder.time_base());
+    if let Some(tag) = tag {
+        unsafe {
+            (*(*stream.as_mut_ptr()).codecpar).codec_tag = tag;
+        }
+    }
+    Ok(stream.index())
+}
+
+fn options(options: &[(String, String)]) -> Dictionary<'static> {
+    let mut dictionary = Dictionary::new();
+    for (key, value) in options {
+        dictionary.set(key, value);
+    }
+    dictionary
+}
+
+/// `hvc1` as the little endian number containers store it as
+fn fourcc(tag: &str) -> anyhow::Result<u32> {
+    let bytes: [u8; 4] = tag
+        .as_bytes()
+        .try_into()
+        .with_context(|| format!("Bad tag {tag}, expected 4 characters"))?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn pass_1_only_runs_the_video_encoder() {
+        let invocation = Invocation::parse(&args(
+            "-y -ss 10 -i in.mp4 -t 5 -vcodec libx264 -acodec aac -vf scale=-2:720 -b:a 96k \
+             -b:v 1000k -preset slow -profile:v high -pix_fmt yuv420p -threads 4 \
+             -pass 1 -passlogfile /tmp/log -f null /dev/null",
+        ))
+        .unwrap();
+        assert_eq!(invocation.input, PathBuf::from("in.mp4"));
+        assert_eq!(invocation.start, Some(10.));
+        assert_eq!(invocation.duration, Some(5.));
+        assert_eq!(invocation.output, None);
+        assert_eq!(invocation.passlog(), Some(PathBuf::from("/tmp/log-0.log")));
+        let video = &invocation.video;
+        assert_eq!(video.encoder.as_deref(), Some("libx264"));
+        assert_eq!(video.filters.as_deref(), Some("scale=-2:720"));
+        assert_eq!(video.pix_fmt.as_deref(), Some("yuv420p"));
+        assert_eq!(
+            video.options,
+            [
+                ("b", "1000k"),
+                ("preset", "slow"),
+                ("profile", "high"),
+                ("threads", "4"),
+                ("stats", "/tmp/log-0.log"),
+            ]
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        );
+        let audio = invocation.audio.unwrap();
+        assert_eq!(audio.encoder.as_deref(), Some("aac"));
+        assert_eq!(
+            audio.options,
+            [("b", "96k"), ("preset", "slow"), ("threads", "4")]
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        );
+    }
+
+    #[test]
+    fn images_keep_their_muxer_options_and_quality() {
+        let invocation = Invocation::parse(&args(
+            "-y -i in.gif -vf fps=10 -c:v libwebp -loop 0 -an -fps_mode passthrough \
+             -qscale 75 -compression_level 6 out.webp",
+        ))
+        .unwrap();
+        assert_eq!(invocation.output, Some(PathBuf::from("out.webp")));
+        assert_eq!(invocation.audio, None);
+        assert_eq!(invocation.muxer, [("loop".to_owned(), "0".to_owned())]);
+        assert_eq!(invocation.video.qscale, Some(75.));
+        assert_eq!(
+            invocation.video.options,
+            [("compression_level".to_owned(), "6".to_owned())]
+        );
+    }
+
+    #[test]
+    fn options_without_a_value_are_an_error() {
+        assert!(Invocation::parse(&args("-y -i in.mp4 -vcodec")).is_err());
+        assert!(Invocation::parse(&args("-y -vcodec libx264 out.mp4")).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+use anyhow::bail;
+
+use crate::plan::Constraints;
+use crate::size::TargetSize;
+
+pub const MAX_OPUS_BITRATE: f32 = 256.; //kbits
+pub const MIN_OPUS_BITRATE: f32 = 50.; //kbits
+/// Share of the size audio only outputs get, the rest is headroom for the container
+pub const AUDIO_ONLY_SHARE: f32 = 0.85;
+/// Shares of the size the audio and video of a video get, the rest is headroom for the
+/// container
+pub const AUDIO_SHARE: f32 = 0.18;
+pub const VIDEO_SHARE: f32 = 0.78;
+/// Less than this isn't worth encoding
+pub const MIN_VIDEO_BITRATE: f32 = 10.; //kbits
+/// More than any encoder makes use of, keeps very short clips with a big size sane
+pub const MAX_VIDEO_BITRATE: f32 = 500_000.; //kbits
+/// Videos longer than the duration (secs) get scaled down to the height, if they're taller
+const HEIGHT_LADDER: [(f32, u16); 3] = [(150., 1080), (600., 720), (900., 480)];
+
+/// What a video gets encoded with to fit its size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoPlan {
+    pub video_bitrate: f32, //kbits
+    pub audio_bitrate: f32, //kbits
+    pub height: u16,
+}
+
+/// Plans a video of `duration` secs and `source_height` pixels within `constraints`
+pub fn plan_video(
+    duration: f32,
+    source_height: u16,
+    constraints: &Constraints,
+) -> anyhow::Result<VideoPlan> {
+    let (video_bitrate, audio_bitrate) = video_bitrates(constraints.size, duration)?;
+    Ok(VideoPlan {
+        video_bitrate,
+        audio_bitrate,
+        height: scaled_height(source_height, duration, constraints.max_height),
+    })
+}
+
+/// Bitrate for `duration` secs of audio only, never more than the source had. Whole kbits,
+/// rounded down so it stays under the size.
+pub fn audio_bitrate(
+    size: TargetSize,
+    duration: f32,
+    source_kbit_rate: Option<u32>,
+) -> anyhow::Result<f32> {
+    check_duration(duration)?;
+    let budget = size.kbits() * AUDIO_ONLY_SHARE / duration;
+    if budget < MIN_OPUS_BITRATE {
+        bail!(
+            "{size} is too small for {duration}s of audio, it needs at least {}",
+            min_size(MIN_OPUS_BITRATE * duration / AUDIO_ONLY_SHARE)
+        );
+    }
+    // a source under the minimum still gets the minimum, the encoders don't go lower
+    let max = source_kbit_rate.map_or(MAX_OPUS_BITRATE, |r| {
+        (r as f32).clamp(MIN_OPUS_BITRATE, MAX_OPUS_BITRATE)
+    });
+    Ok(budget.min(max).floor())
+}
+
+/// Splits the size between audio and video and returns their (video, audio) bitrates. Audio
+/// kept within the opus limits gives or takes from the video. Whole kbits, rounded down so
+/// they stay under the size.
+pub fn video_bitrates(size: TargetSize, duration: f32) -> anyhow::Result<(f32, f32)> {
+    check_duration(duration)?;
+    let kbit_rate = size.kbits() / duration;
+    let audio_bitrate = (kbit_rate * AUDIO_SHARE).clamp(MIN_OPUS_BITRATE, MAX_OPUS_BITRATE);
+    let overflow = kbit_rate * AUDIO_SHARE - audio_bitrate;
+    let video_bitrate = kbit_rate * VIDEO_SHARE + overflow;
+    if video_bitrate < MIN_VIDEO_BITRATE {
+        bail!(
+            "{size} is too small for {duration}s of video, it needs at least {}",
+            min_size(
+                (MIN_VIDEO_BITRATE + MIN_OPUS_BITRATE) * duration / (AUDIO_SHARE + VIDEO_SHARE)
+            )
+        );
+    }
+    Ok((
+        video_bitrate.min(MAX_VIDEO_BITRATE).floor(),
+        audio_bitrate.floor(),
+    ))
+}
+
+/// Height a video gets scaled to: long videos step down to 1080p, 720p and 480p so the
+/// bitrate doesn't get spread too thin, and `max_height` caps it further. Never upscales.
+pub fn scaled_height(source_height: u16, duration: f32, max_height: Option<u16>) -> u16 {
+    let mut height = source_height;
+    for (min_duration, ladder_height) in HEIGHT_LADDER {
+        if duration > min_duration {
+            height = height.min(ladder_height);
+        }
+    }
+    match max_height {
+        Some(max_height) => height.min(max_height),
+        None => height,
+    }
+}
+
+fn check_duration(duration: f32) -> anyhow::Result<()> {
+    if !(duration.is_finite() && duration > 0.) {
+        bail!("can't plan for a duration of {duration}s");
+    }
+    Ok(())
+}
+
+/// Smallest size `kbits` fit in, rounded up to whole kilobytes
+fn min_size(kbits: f32) -> TargetSize {
+    TargetSize::from_bytes((kbits / 8.).ceil() as u64 * 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ImageFormat, VideoCodec};
+    use proptest::prelude::*;
+
+    const MB: u64 = 1_000_000;
+
+    fn size(bytes: u64) -> TargetSize {
+        TargetSize::from_bytes(bytes)
+    }
+
+    fn constraints(size: TargetSize, max_height: Option<u16>) -> Constraints {
+        Constraints {
+            size,
+            codec: VideoCodec::WEBM,
+            audio_codec: None,
+            max_height,
+            max_fps: None,
+            max_duration: None,
+            split: false,
+            image_format: ImageFormat::WEBP,
+        }
+    }
+
+    #[test]
+    fn audio_gets_85_percent_of_the_size() {
+        // 10MB over 300s is 266.6k, 85% of it 226.6k
+        assert_eq!(audio_bitrate(size(10 * MB), 300., None).unwrap(), 226.);
+    }
+
+    #[test]
+    fn audio_never_goes_over_the_source_or_opus_max() {
+        assert_eq!(audio_bitrate(size(10 * MB), 300., Some(128)).unwrap(), 128.);
+        assert_eq!(audio_bitrate(size(500 * MB), 60., None).unwrap(), 256.);
+        assert_eq!(audio_bitrate(size(500 * MB), 60., Some(320)).unwrap(), 256.);
+    }
+
+    #[test]
+    fn audio_from_a_low_bitrate_source_gets_the_opus_min() {
+        assert_eq!(audio_bitrate(size(10 * MB), 60., Some(32)).unwrap(), 50.);
+    }
+
+    #[test]
+    fn audio_that_cant_fit_is_an_error() {
+        // 1MB is 8000 kbits, 85% of it only gives 6.8k over 1000s
+        assert!(audio_bitrate(size(MB), 1000., None).is_err());
+    }
+
+    #[test]
+    fn video_splits_18_78_between_audio_and_video() {
+        // 25MB over 600s is 333.3k
+        assert_eq!(video_bitrates(size(25 * MB), 600.).unwrap(), (260., 60.));
+    }
+
+    #[test]
+    fn audio_under_the_opus_min_comes_out_of_the_video() {
+        // 10MB over 600s is 133.3k, audio would get 24k but needs 50k
+        let (video, audio) = video_bitrates(size(10 * MB), 600.).unwrap();
+        assert_eq!(audio, MIN_OPUS_BITRATE);
+        assert!((77. ..=78.).contains(&video), "{video}");
+    }
+
+    #[test]
+    fn audio_over_the_opus_max_goes_to_the_video() {
+        // 500MB over 60s is 66666.6k, audio would get 12000k
+        let (video, audio) = video_bitrates(size(500 * MB), 60.).unwrap();
+        assert_eq!(audio, MAX_OPUS_BITRATE);
+        assert!((63743. ..=63744.).contains(&video), "{video}");
+    }
+
+    #[test]
+    fn short_clips_with_a_big_size_dont_overflow() {
+        let (video, audio) = video_bitrates(size(2_000 * MB), 0.04).unwrap();
+        assert_eq!(video, MAX_VIDEO_BITRATE);
+        assert_eq!(audio, MAX_OPUS_BITRATE);
+        assert_eq!(video as u32, 500_000);
+    }
+
+    #[test]
+    fn video_that_cant_fit_is_an_error() {
+        // 1MB over an hour leaves nothing for the video after the audio minimum
+        assert!(video_bitrates(size(MB), 3600.).is_err());
+    }
+
+    #[test]
+    fn bad_durations_are_errors() {
+        for duration in [0., -1., f32::NAN, f32::INFINITY] {
+            assert!(video_bitrates(size(25 * MB), duration).is_err());
+            assert!(audio_bitrate(size(25 * MB), duration, None).is_err());
+        }
+    }
+
+    #[test]
+    fn long_videos_step_down_in_height() {
+        assert_eq!(scaled_height(2160, 100., None), 2160);
+        assert_eq!(scaled_height(2160, 200., None), 1080);
+        assert_eq!(scaled_height(2160, 700., None), 720);
+        assert_eq!(scaled_height(2160, 1000., None), 480);
+        // sources between two steps only go down once the next one is reached
+        assert_eq!(scaled_height(1000, 200., None), 1000);
+        assert_eq!(scaled_height(1000, 700., None), 720);
+        assert_eq!(scaled_height(360, 1000., None), 360);
+    }
+
+    #[test]
+    fn max_height_caps_the_ladder() {
+        assert_eq!(scaled_height(2160, 100., Some(720)), 720);
+        assert_eq!(scaled_height(2160, 1000., Some(720)), 480);
+        assert_eq!(scaled_height(480, 100., Some(720)), 480);
+    }
+
+    #[test]
+    fn plan_video_combines_bitrates_and_height() {
+        let plan = plan_video(600., 1080, &constraints(size(25 * MB), None)).unwrap();
+        assert_eq!(
+            plan,
+            VideoPlan {
+                video_bitrate: 260.,
+                audio_bitrate: 60.,
+                height: 1080,
+            }
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn video_never_exceeds_the_size(bytes in 1_000u64..10_000 * MB, duration in 0.01f32..100_000.) {
+            if let Ok((video, audio)) = video_bitrates(size(bytes), duration) {
+                prop_assert!((video + audio) * duration <= size(bytes).kbits());
+            }
+        }
+
+        #[test]
+        fn video_bitrates_are_whole_kbits_in_range(bytes in 1_000u64..10_000 * MB, duration in 0.01f32..100_000.) {
+            if let Ok((video, audio)) = video_bitrates(size(bytes), duration) {
+                prop_assert!((MIN_VIDEO_BITRATE..=MAX_VIDEO_BITRATE).contains(&video));
+                prop_assert!((MIN_OPUS_BITRATE..=MAX_OPUS_BITRATE).contains(&audio));
+                // nothing gets lost or wraps once ffmpeg gets them as `{}k`
+                prop_assert_eq!(video as u32 as f32, video);
+                prop_assert_eq!(audio as u32 as f32, audio);
+            }
+        }
+
+        #[test]
+        fn video_fits_whenever_the_minimums_do(bytes in 1_000u64..10_000 * MB, duration in 0.01f32..100_000.) {
+            let needed = (MIN_VIDEO_BITRATE + MIN_OPUS_BITRATE) * duration / (AUDIO_SHARE + VIDEO_SHARE);
+            if size(bytes).kbits() >= needed * 1.001 {
+                prop_assert!(video_bitrates(size(bytes), duration).is_ok());
+            }
+        }
+
+        #[test]
+        fn audio_never_exceeds_the_size_or_opus_limits(
+            bytes in 1_000u64..10_000 * MB,
+            duration in 0.01f32..100_000.,
+            source in proptest::option::of(1u32..2_000),
+        ) {
+            if let Ok(bitrate) = audio_bitrate(size(bytes), duration, source) {
+                prop_assert!(bitrate * duration <= size(bytes).kbits() * AUDIO_ONLY_SHARE);
+                prop_assert!((MIN_OPUS_BITRATE..=MAX_OPUS_BITRATE).contains(&bitrate));
+                if let Some(source) = source {
+                    prop_assert!(bitrate <= (source as f32).max(MIN_OPUS_BITRATE));
+                }
+                prop_assert_eq!(bitrate as u32 as f32, bitrate);
+            }
+        }
+
+        #[test]
+        fn height_never_grows(
+            source in 1u16..8640,
+            duration in 0f32..100_000.,
+            longer in 0f32..100_000.,
+            max_height in proptest::option::of(1u16..4320),
+        ) {
+            let height = scaled_height(source, duration, max_height);
+            prop_assert!(height <= source);
+            prop_assert!(max_height.is_none_or(|max| height <= max));
+            prop_assert!(scaled_height(source, duration + longer, max_height) <= height);
+        }
+    }
+}
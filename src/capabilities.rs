@@ -0,0 +1,371 @@
+use anyhow::bail;
+use std::collections::{BTreeMap, HashSet};
+
+use crate::plan::{Constraints, Job, MediaType};
+use crate::{AudioCodec, VideoCodec};
+
+/// `-stats_period`, which progress reporting relies on, is new in 4.4
+const MIN_VERSION: (u32, u32) = (4, 4);
+/// `-fps_mode`, which animated images use, is new in 5.1
+const FPS_MODE_VERSION: (u32, u32) = (5, 1);
+/// Filters videos and images get scaled, frame rate capped and frame dropped with
+pub(crate) const FILTERS: [&str; 3] = ["scale", "fps", "select"];
+/// What a missing audio encoder gets replaced with, first one that's there and fits wins
+const AUDIO_FALLBACKS: [AudioCodec; 4] = [
+    AudioCodec::OPUS,
+    AudioCodec::OPUSNATIVE,
+    AudioCodec::VORBIS,
+    AudioCodec::AAC,
+];
+
+/// What an ffmpeg build can do, from its `-version`, `-encoders` and `-filters` output
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// (major, minor), `None` for git builds which don't have one. Those are assumed to be
+    /// recent.
+    version: Option<(u32, u32)>,
+    encoders: HashSet<String>,
+    filters: HashSet<String>,
+}
+
+impl Capabilities {
+    pub fn parse(version: &str, encoders: &str, filters: &str) -> Self {
+        Capabilities {
+            version: parse_version(version),
+            // the list starts after a ` ------` line, `<flags> <name> <description>`
+            encoders: encoders
+                .lines()
+                .skip_while(|l| !l.trim().starts_with("---"))
+                .skip(1)
+                .filter_map(|l| l.split_whitespace().nth(1))
+                .map(str::to_owned)
+                .collect(),
+            // `<flags> <name> <in>-><out> <description>`, after a legend without arrows
+            filters: filters
+                .lines()
+                .filter_map(|l| {
+                    let mut columns = l.split_whitespace().skip(1);
+                    let name = columns.next()?;
+                    columns.next()?.contains("->").then(|| name.to_owned())
+                })
+                .collect(),
+        }
+    }
+
+    /// For linked libraries, which have no version worth checking against
+    pub fn new(
+        encoders: impl IntoIterator<Item = String>,
+        filters: impl IntoIterator<Item = String>,
+    ) -> Self {
+        Capabilities {
+            version: None,
+            encoders: encoders.into_iter().collect(),
+            filters: filters.into_iter().collect(),
+        }
+    }
+
+    pub fn has_encoder(&self, encoder: &str) -> bool {
+        self.encoders.contains(encoder)
+    }
+
+    /// Checks ffmpeg can encode within `constraints` at all, switching to another audio codec
+    /// if the one they ask for is missing. Returns a note on what got switched. Whether the
+    /// video encoder is there is up to [`Self::check_jobs`], only videos need it.
+    pub fn check(&self, constraints: &mut Constraints) -> anyhow::Result<Option<String>> {
+        if let Some(version) = self.version.filter(|v| *v < MIN_VERSION) {
+            bail!(
+                "ffmpeg {}.{} is too old, nmb needs at least {}.{}",
+                version.0,
+                version.1,
+                MIN_VERSION.0,
+                MIN_VERSION.1
+            );
+        }
+        let missing = FILTERS
+            .into_iter()
+            .filter(|f| !self.filters.contains(*f))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            bail!(
+                "ffmpeg was built without the {} filters",
+                missing.join(", ")
+            );
+        }
+
+        let profile = constraints.codec.profile();
+        // videos use the audio codec of their video codec, audio only files opus, unless
+        // the constraints say otherwise
+        let wanted = constraints.audio_codec.unwrap_or(profile.audio_codec());
+        let mut needed = match constraints.audio_codec {
+            Some(audio_codec) => vec![audio_codec],
+            None => vec![wanted, AudioCodec::OPUS],
+        };
+        needed.dedup();
+        let missing = needed
+            .into_iter()
+            .filter(|a| !self.has_encoder(a.encoder()))
+            .map(|a| a.encoder())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(None);
+        }
+        let Some(fallback) = [wanted]
+            .into_iter()
+            .chain(AUDIO_FALLBACKS)
+            .find(|a| self.has_encoder(a.encoder()) && a.fits(profile.extension()))
+        else {
+            bail!(
+                "ffmpeg has no {} encoder, nor any other audio encoder that goes in .{}",
+                missing.join(" or "),
+                profile.extension()
+            );
+        };
+        constraints.audio_codec = Some(fallback);
+        Ok(Some(format!(
+            "ffmpeg has no {} encoder, using {} instead",
+            missing.join(" or "),
+            fallback.encoder()
+        )))
+    }
+
+    /// Checks ffmpeg has everything the planned `jobs` use, stills nmb encodes in-process
+    /// don't need it at all
+    pub fn check_jobs(&self, jobs: &[Job]) -> anyhow::Result<()> {
+        if let Some(codec) = jobs.iter().find_map(Job::video_codec) {
+            self.check_video_codec(codec)?;
+        }
+        let mut missing = BTreeMap::<String, Vec<&str>>::new();
+        for job in jobs.iter().filter(|j| j.still().is_none()) {
+            for encoder in job.codec().split('+') {
+                if !self.has_encoder(encoder) {
+                    missing
+                        .entry(format!("no {encoder} encoder"))
+                        .or_default()
+                        .push(job.label());
+                }
+            }
+            if job.media_type() == MediaType::AnimatedImage
+                && self.version.is_some_and(|v| v < FPS_MODE_VERSION)
+            {
+                missing
+                    .entry(format!(
+                        "animated images need ffmpeg {}.{}",
+                        FPS_MODE_VERSION.0, FPS_MODE_VERSION.1
+                    ))
+                    .or_default()
+                    .push(job.label());
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let reasons = missing
+            .into_iter()
+            .map(|(reason, labels)| format!("{reason} (for {})", labels.join(", ")))
+            .collect::<Vec<_>>();
+        bail!("ffmpeg can't do every job: {}", reasons.join("; "))
+    }
+
+    /// Suggests the codecs that can be used instead if `codec`s encoder is missing
+    fn check_video_codec(&self, codec: &VideoCodec) -> anyhow::Result<()> {
+        let profile = codec.profile();
+        if !self.has_encoder(profile.video_encoder()) {
+            let usable = [
+                VideoCodec::WEBM,
+                VideoCodec::HEVC,
+                VideoCodec::AV1,
+                VideoCodec::AV1MP4,
+                VideoCodec::H264,
+            ]
+            .into_iter()
+            .filter(|c| self.has_encoder(c.profile().video_encoder()))
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+            bail!(
+                "ffmpeg has no {} encoder for {}, {}",
+                profile.video_encoder(),
+                codec,
+                match usable.is_empty() {
+                    true => "nor any other video encoder nmb uses".to_owned(),
+                    false => format!("pick another --codec: {}", usable.join(", ")),
+                }
+            );
+        }
+        Ok(())
+    }
+}
+
+/// `ffmpeg version 6.1.1-3ubuntu5 Copyright...`, `ffmpeg version n7.0 ...`
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let number = version
+        .lines()
+        .next()?
+        .split_whitespace()
+        .nth(2)?
+        .trim_start_matches('n');
+    let mut parts = number.split('.');
+    let major = leading_number(parts.next()?)?;
+    let minor = parts.next().and_then(leading_number).unwrap_or(0);
+    Some((major, minor))
+}
+
+fn leading_number(part: &str) -> Option<u32> {
+    let digits = part
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect::<String>();
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::size::TargetSize;
+    use crate::ImageFormat;
+
+    const ENCODERS: &str = "Encoders:
+ V..... = Video
+ A..... = Audio
+ S..... = Subtitle
+ .F.... = Frame-level multithreading
+ ..S... = Slice-level multithreading
+ ...X.. = Codec is experimental
+ ....B. = Supports draw_horiz_band
+ .....D = Supports direct rendering method 1
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10 (codec h264)
+ V....D libvpx-vp9           libvpx VP9 (codec vp9)
+ V....D libwebp              libwebp WebP image (codec webp)
+ A....D aac                  AAC (Advanced Audio Coding)
+ A..X.D opus                 Opus
+ A....D libvorbis            libvorbis (codec vorbis)
+";
+
+    const FILTERS_OUTPUT: &str = "Filters:
+  T.. = Timeline support
+  .S. = Slice threading
+  ..C = Command support
+  A = Audio input/output
+  V = Video input/output
+  N = Dynamic number and/or type of input/output
+  | = Source or sink filter
+ ... abench            A->A       Benchmark part of a filtergraph.
+ ..C fps               V->V       Force constant framerate.
+ ..C scale             V->V       Scale the input video size and/or convert the image format.
+ T.. select            V->N       Select video frames to pass in output.
+";
+
+    fn capabilities(version: &str) -> Capabilities {
+        Capabilities::parse(version, ENCODERS, FILTERS_OUTPUT)
+    }
+
+    fn constraints(codec: VideoCodec, audio_codec: Option<AudioCodec>) -> Constraints {
+        Constraints {
+            size: TargetSize::from_bytes(10_000_000),
+            codec,
+            audio_codec,
+            max_height: None,
+            max_fps: None,
+            max_duration: None,
+            split: false,
+            image_format: ImageFormat::WEBP,
+        }
+    }
+
+    #[test]
+    fn versions_parse() {
+        for (version, parsed) in [
+            (
+                "ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023",
+                Some((6, 1)),
+            ),
+            ("ffmpeg version n7.0 Copyright (c) 2000-2024", Some((7, 0))),
+            ("ffmpeg version 4.2.7-0ubuntu0.1 Copyright", Some((4, 2))),
+            ("ffmpeg version 7 Copyright", Some((7, 0))),
+            ("ffmpeg version N-113450-g1a2b3c4d5e Copyright", None),
+            ("", None),
+        ] {
+            assert_eq!(parse_version(version), parsed, "{version}");
+        }
+    }
+
+    #[test]
+    fn encoders_and_filters_parse() {
+        let capabilities = capabilities("ffmpeg version 6.1");
+        assert!(capabilities.has_encoder("libvpx-vp9"));
+        assert!(capabilities.has_encoder("opus"));
+        assert!(!capabilities.has_encoder("libopus"));
+        // the legend isn't a list of encoders
+        assert!(!capabilities.has_encoder("="));
+        assert_eq!(
+            capabilities.filters,
+            HashSet::from(["abench", "fps", "scale", "select"].map(str::to_owned))
+        );
+    }
+
+    #[test]
+    fn old_ffmpeg_is_an_error() {
+        let mut constraints = constraints(VideoCodec::WEBM, None);
+        let error = capabilities("ffmpeg version 4.2.7")
+            .check(&mut constraints)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "ffmpeg 4.2 is too old, nmb needs at least 4.4"
+        );
+    }
+
+    #[test]
+    fn missing_video_encoder_suggests_the_usable_codecs() {
+        let capabilities = capabilities("ffmpeg version 6.1");
+        // only videos need one
+        capabilities
+            .check(&mut constraints(VideoCodec::HEVC, None))
+            .unwrap();
+        let error = capabilities
+            .check_video_codec(&VideoCodec::HEVC)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "ffmpeg has no libx265 encoder for HEVC, pick another --codec: WEBM, H264"
+        );
+    }
+
+    #[test]
+    fn missing_libopus_falls_back_to_native_opus() {
+        let mut constraints = constraints(VideoCodec::WEBM, None);
+        let note = capabilities("ffmpeg version 6.1")
+            .check(&mut constraints)
+            .unwrap();
+        assert_eq!(constraints.audio_codec, Some(AudioCodec::OPUSNATIVE));
+        assert_eq!(
+            note.as_deref(),
+            Some("ffmpeg has no libopus encoder, using opus instead")
+        );
+    }
+
+    #[test]
+    fn missing_libopus_falls_back_to_vorbis_in_webm_and_aac_in_mp4() {
+        let encoders = ENCODERS.replace(" opus ", " nope ");
+        let capabilities = Capabilities::parse("ffmpeg version 6.1", &encoders, FILTERS_OUTPUT);
+
+        let mut webm = constraints(VideoCodec::WEBM, None);
+        capabilities.check(&mut webm).unwrap();
+        assert_eq!(webm.audio_codec, Some(AudioCodec::VORBIS));
+
+        // h264 keeps its aac, audio only files switch to it too
+        let mut mp4 = constraints(VideoCodec::H264, None);
+        capabilities.check(&mut mp4).unwrap();
+        assert_eq!(mp4.audio_codec, Some(AudioCodec::AAC));
+    }
+
+    #[test]
+    fn available_codecs_are_left_alone() {
+        let mut constraints = constraints(VideoCodec::H264, Some(AudioCodec::AAC));
+        let note = capabilities("ffmpeg version N-113450-g1a2b3c4d5e")
+            .check(&mut constraints)
+            .unwrap();
+        assert_eq!(note, None);
+        assert_eq!(constraints.audio_codec, Some(AudioCodec::AAC));
+    }
+}
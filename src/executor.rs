@@ -0,0 +1,384 @@
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::{
+    sync::{mpsc, watch, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::backend::{Backend, Pass, Progress};
+use crate::plan::{ImageSettings, Job, MediaType};
+use crate::probe::native;
+use crate::still::Source;
+
+/// ffmpeg reports progress every 50ms, events only go out this often and when it moved
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What happens to the jobs of a [`Run`], in order per job. `job` is the index the job had
+/// when it was handed to [`Executor::new`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Queued {
+        job: usize,
+        input: PathBuf,
+        output: PathBuf,
+        media_type: MediaType,
+    },
+    PassStarted {
+        job: usize,
+        /// Only two-pass video jobs have passes
+        pass: Option<u8>,
+        attempt: u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        image: Option<ImageSettings>,
+    },
+    Progress {
+        job: usize,
+        /// Seconds, for in-process stills the share that's done with a `duration` of 1
+        out_time: f32,
+        duration: Option<f32>,
+        speed: Option<f32>,
+        size: u64, //bytes
+    },
+    Finished {
+        job: usize,
+        output: PathBuf,
+        size: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        image: Option<ImageSettings>,
+    },
+    Failed {
+        job: usize,
+        error: String,
+    },
+    Cancelled {
+        job: usize,
+    },
+}
+
+/// How a job ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobStatus {
+    Finished,
+    /// With a reason readable in one line
+    Failed(String),
+    Cancelled,
+}
+
+pub struct JobResult {
+    /// The job as it ended, with the bitrate and attempts of its last encode
+    pub job: Job,
+    pub status: JobStatus,
+    /// Last lines ffmpeg wrote to stderr if it failed
+    pub stderr_tail: Vec<String>,
+    /// Wall time from the first encode starting to the job ending, either way
+    pub elapsed: Option<Duration>,
+    pub output_size: Option<u64>, //bytes
+}
+
+/// Runs planned jobs on a [`Backend`], a few at a time
+pub struct Executor<B> {
+    backend: Arc<B>,
+    jobs: Vec<Job>,
+    parallel: usize,
+}
+
+impl<B: Backend> Executor<B> {
+    /// Runs at most `parallel` jobs at once, splitting the cpu threads between them
+    pub fn new(backend: Arc<B>, mut jobs: Vec<Job>, parallel: usize) -> Self {
+        let parallel = parallel.max(1);
+        let cpus = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let running = parallel.min(jobs.len()).max(1);
+        let threads = (cpus / running).max(1) as u16;
+        for job in jobs.iter_mut() {
+            job.set_threads(threads);
+        }
+        Executor {
+            backend,
+            jobs,
+            parallel,
+        }
+    }
+
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    /// Starts every job in the background. Must be called within a tokio runtime.
+    pub fn start(self) -> Run {
+        let (events, receiver) = mpsc::unbounded_channel();
+        let (cancel, cancelled) = watch::channel(false);
+        let queue = Arc::new(Semaphore::new(self.parallel));
+        for (index, job) in self.jobs.iter().enumerate() {
+            let _ = events.send(Event::Queued {
+                job: index,
+                input: job.input().to_path_buf(),
+                output: job.output().to_path_buf(),
+                media_type: job.media_type(),
+            });
+        }
+        let tasks = self
+            .jobs
+            .into_iter()
+            .enumerate()
+            .map(|(index, job)| {
+                tokio::spawn(run_job(
+                    self.backend.clone(),
+                    index,
+                    job,
+                    queue.clone(),
+                    events.clone(),
+                    cancelled.clone(),
+                ))
+            })
+            .collect();
+        Run {
+            events: receiver,
+            cancel,
+            tasks,
+        }
+    }
+}
+
+/// Jobs started by [`Executor::start`]
+pub struct Run {
+    events: mpsc::UnboundedReceiver<Event>,
+    cancel: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<JobResult>>,
+}
+
+impl Run {
+    /// Next thing that happened, `None` once every job ended
+    pub async fn next_event(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+
+    /// Stops every job that hasn't finished yet. Running ffmpegs get killed, and what they
+    /// wrote so far is removed.
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+
+    /// Waits for every job to end
+    pub async fn results(self) -> anyhow::Result<Vec<JobResult>> {
+        let cancelled = *self.cancel.borrow();
+        let mut results = vec![];
+        for task in self.tasks {
+            let mut result = task.await.context("Job panicked")?;
+            // ffmpeg gets the Ctrl-C too, so jobs may have just failed because of it
+            if cancelled && matches!(result.status, JobStatus::Failed(_)) {
+                result.status = JobStatus::Cancelled;
+                result.stderr_tail.clear();
+            }
+            results.push(result);
+        }
+        Ok(results)
+    }
+}
+
+/// Why a job failed
+struct Failure {
+    reason: String,
+    stderr_tail: Vec<String>,
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(e: anyhow::Error) -> Self {
+        Failure {
+            reason: format!("{e:#}"),
+            stderr_tail: vec![],
+        }
+    }
+}
+
+async fn run_job<B: Backend>(
+    backend: Arc<B>,
+    index: usize,
+    mut job: Job,
+    queue: Arc<Semaphore>,
+    events: mpsc::UnboundedSender<Event>,
+    mut cancelled: watch::Receiver<bool>,
+) -> JobResult {
+    let mut started = None;
+    let outcome = tokio::select! {
+        outcome = async {
+            let _permit = queue.acquire().await.context("Job queue closed")?;
+            started = Some(Instant::now());
+            encode(&*backend, index, &mut job, &events).await
+        } => Some(outcome),
+        // dropping the encode kills the ffmpeg it's waiting on
+        Ok(_) = cancelled.wait_for(|c| *c) => None,
+    };
+    let elapsed = started.map(|s| s.elapsed());
+
+    if outcome.as_ref().is_none_or(|o| o.is_err()) {
+        let _ = std::fs::remove_file(job.partial());
+    }
+    for log in job.passlogs() {
+        let _ = std::fs::remove_file(log);
+    }
+    let (status, stderr_tail, output_size) = match outcome {
+        Some(Ok(())) => {
+            let size = std::fs::metadata(job.output()).ok().map(|m| m.len());
+            let _ = events.send(Event::Finished {
+                job: index,
+                output: job.output().to_path_buf(),
+                size,
+                image: job.image_settings(),
+            });
+            (JobStatus::Finished, vec![], size)
+        }
+        Some(Err(failure)) => {
+            let _ = events.send(Event::Failed {
+                job: index,
+                error: failure.reason.clone(),
+            });
+            (JobStatus::Failed(failure.reason), failure.stderr_tail, None)
+        }
+        None => {
+            let _ = events.send(Event::Cancelled { job: index });
+            (JobStatus::Cancelled, vec![], None)
+        }
+    };
+    JobResult {
+        job,
+        status,
+        stderr_tail,
+        elapsed,
+        output_size,
+    }
+}
+
+/// Runs every pass and size attempt of `job`, then moves the verified output into place
+async fn encode(
+    backend: &impl Backend,
+    index: usize,
+    job: &mut Job,
+    events: &mpsc::UnboundedSender<Event>,
+) -> Result<(), Failure> {
+    let source = match job.still() {
+        Some(_) => Some(Source::decode(job.input()).await?),
+        None => None,
+    };
+    let mut pass = 1;
+    loop {
+        let _ = events.send(Event::PassStarted {
+            job: index,
+            pass: (job.passes() > 1).then_some(pass),
+            attempt: job.size_attempts(),
+            image: job.image_settings(),
+        });
+        match job.still().zip(source.as_ref()) {
+            // in-process encodes report how much of them is done, out of 1
+            Some((still, source)) => run_pass(index, source.start(still), Some(1.), events).await?,
+            None => {
+                let started = backend.start(&job.args(pass)?)?;
+                run_pass(index, started, job.duration(), events).await?
+            }
+        }
+        if pass < job.passes() {
+            pass += 1;
+            continue;
+        }
+        let size = std::fs::metadata(job.partial())
+            .context("ffmpeg exited without writing an output")?
+            .len();
+        if !job.retry(size)? {
+            break;
+        }
+    }
+    verify_output(backend, job.partial(), source.is_some()).await?;
+    std::fs::rename(job.partial(), job.output())
+        .with_context(|| format!("Can't move output to {}", job.output().display()))?;
+    Ok(())
+}
+
+/// Runs one started encode to the end, turning its progress into events
+async fn run_pass(
+    index: usize,
+    mut pass: impl Pass,
+    duration: Option<f32>,
+    events: &mpsc::UnboundedSender<Event>,
+) -> Result<(), Failure> {
+    let send = |progress: Progress| {
+        let _ = events.send(Event::Progress {
+            job: index,
+            out_time: progress.out_time,
+            duration,
+            speed: progress.speed,
+            size: progress.size,
+        });
+    };
+    let mut last_sent: Option<(Instant, f32)> = None;
+    let mut unsent = None;
+    while let Some(progress) = pass.progress().await {
+        let due = last_sent.is_none_or(|(at, time)| {
+            at.elapsed() >= PROGRESS_INTERVAL && time != progress.out_time
+        });
+        match due {
+            true => {
+                last_sent = Some((Instant::now(), progress.out_time));
+                unsent = None;
+                send(progress);
+            }
+            false => unsent = Some(progress),
+        }
+    }
+    // where the encode ended up always gets reported
+    if let Some(progress) = unsent.filter(|p| last_sent.is_none_or(|(_, t)| t != p.out_time)) {
+        send(progress);
+    }
+
+    let exit = pass.wait().await?;
+    if exit.success() {
+        return Ok(());
+    }
+    // ffmpeg ends on a generic "Conversion failed!", the actual error is further up
+    let reason = exit
+        .stderr_tail
+        .iter()
+        .rev()
+        .find(|l| l.to_lowercase().contains("error"))
+        .or(exit.stderr_tail.last())
+        .map_or("no output".to_owned(), |l| l.trim().to_owned());
+    let code = match exit.code {
+        Some(code) => format!("exit code {code}"),
+        None => "killed".to_owned(),
+    };
+    Err(Failure {
+        reason: format!("ffmpeg {code}: {reason}"),
+        stderr_tail: exit.stderr_tail,
+    })
+}
+
+/// Checks an encode produced a non-empty file ffprobe can read, before it replaces anything.
+/// What nmb encoded `in_process` only needs to pass its own probe.
+async fn verify_output(
+    backend: &impl Backend,
+    partial: &Path,
+    in_process: bool,
+) -> anyhow::Result<()> {
+    let len = std::fs::metadata(partial)
+        .context("ffmpeg exited without writing an output")?
+        .len();
+    if len == 0 {
+        bail!("ffmpeg wrote an empty output");
+    }
+    let probe = match in_process {
+        true => {
+            let partial = partial.to_path_buf();
+            tokio::task::spawn_blocking(move || native::probe(&partial))
+                .await
+                .context("Probing panicked")?
+        }
+        false => backend.probe(partial).await,
+    };
+    let probe = probe.context("Output is unreadable")?;
+    if probe.streams.is_empty() {
+        bail!("Output has no streams");
+    }
+    Ok(())
+}
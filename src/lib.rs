@@ -0,0 +1,119 @@
+//! Converts media files to fit under a size limit, by driving ffmpeg.
+//!
+//! Files go through three steps: [`backend::Backend::probe`] finds out what a file is,
+//! [`plan::Job::plan`] decides how it gets encoded to fit the [`plan::Constraints`] and where
+//! to, through an [`output::Naming`], and [`executor::Executor`] runs the planned jobs,
+//! reporting [`executor::Event`]s as it goes.
+//! The size math behind the plans is in [`bitrate`]. Probing and encoding go through a
+//! [`backend::Backend`], normally [`backend::Ffmpeg`], except for still images nmb encodes
+//! itself with [`still`].
+
+pub mod backend;
+pub mod bitrate;
+pub mod capabilities;
+pub mod executor;
+pub mod output;
+pub mod plan;
+pub mod probe;
+pub mod profile;
+pub mod size;
+pub mod still;
+pub mod target;
+
+#[derive(Debug, Clone)]
+pub enum VideoCodec {
+    WEBM,
+    HEVC,
+    /// libaom AV1 + opus in a .webm
+    AV1,
+    /// libaom AV1 + opus in a .mp4
+    AV1MP4,
+    /// libx264 + aac in a .mp4, plays back pretty much everywhere
+    H264,
+}
+
+impl std::fmt::Display for VideoCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::WEBM => write!(f, "WEBM"),
+            Self::HEVC => write!(f, "HEVC"),
+            Self::AV1 => write!(f, "AV1"),
+            Self::AV1MP4 => write!(f, "AV1-MP4"),
+            Self::H264 => write!(f, "H264"),
+        }
+    }
+}
+
+impl VideoCodec {
+    pub fn from_string(string: &str) -> Option<Self> {
+        match string.to_lowercase().as_str() {
+            "webm" => Some(Self::WEBM),
+            "hevc" => Some(Self::HEVC),
+            "av1" => Some(Self::AV1),
+            "av1-mp4" => Some(Self::AV1MP4),
+            "h264" => Some(Self::H264),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    OPUS,
+    /// ffmpegs own opus encoder, for builds without libopus
+    OPUSNATIVE,
+    VORBIS,
+    AAC,
+}
+
+impl std::fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::OPUS => write!(f, "OPUS"),
+            Self::OPUSNATIVE => write!(f, "OPUS-NATIVE"),
+            Self::VORBIS => write!(f, "VORBIS"),
+            Self::AAC => write!(f, "AAC"),
+        }
+    }
+}
+
+impl AudioCodec {
+    pub fn from_string(string: &str) -> Option<Self> {
+        match string.to_lowercase().as_str() {
+            "opus" => Some(Self::OPUS),
+            "opus-native" => Some(Self::OPUSNATIVE),
+            "vorbis" => Some(Self::VORBIS),
+            "aac" => Some(Self::AAC),
+            _ => None,
+        }
+    }
+}
+
+/// What still images get written as, when nmb encodes them itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    WEBP,
+    AVIF,
+    JPEG,
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::WEBP => write!(f, "WEBP"),
+            Self::AVIF => write!(f, "AVIF"),
+            Self::JPEG => write!(f, "JPEG"),
+        }
+    }
+}
+
+impl ImageFormat {
+    pub fn from_string(string: &str) -> Option<Self> {
+        match string.to_lowercase().as_str() {
+            "webp" => Some(Self::WEBP),
+            "avif" => Some(Self::AVIF),
+            "jpeg" | "jpg" => Some(Self::JPEG),
+            _ => None,
+        }
+    }
+}
@@ -1,70 +1,123 @@
-#![feature(exit_status_error)]
-
 use anyhow::Context;
-use clap::{arg, command, value_parser};
-use encoder::{FFMPEGCommand, MediaType};
-use std::{path::PathBuf, process::Stdio, sync::Arc};
-use tokio::{
-    io::{AsyncBufReadExt, BufReader},
-    sync::Mutex,
-};
-use ui::display;
+use clap::{arg, command, parser::ValueSource, value_parser, ArgMatches};
+use n_mb::backend::{Backend, Ffmpeg};
+use n_mb::capabilities::Capabilities;
+use n_mb::executor::{Executor, JobResult, JobStatus};
+use n_mb::output::{Collision, OutputNaming, DEFAULT_TEMPLATE};
+use n_mb::plan::{Constraints, Job, MediaType};
+use n_mb::probe::media_type_hint;
+use n_mb::size::TargetSize;
+use n_mb::target::{find_target, load_targets};
+use n_mb::{ImageFormat, VideoCodec};
+use report::{print_event, print_report};
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+use ui::{print_plan, Bars};
 
-use crate::encoder::EncodingStatus;
-mod encoder;
+mod report;
 mod ui;
 
-#[derive(Debug, Clone)]
-pub enum VideoCodec {
-    WEBM,
-    HEVC,
-}
-
-impl std::fmt::Display for VideoCodec {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::WEBM => write!(f, "WEBM"),
-            Self::HEVC => write!(f, "HEVC"),
-        }
-    }
-}
-
-impl VideoCodec {
-    pub fn from_string(string: &str) -> Option<Self> {
-        match string.to_lowercase().as_str() {
-            "webm" => Some(Self::WEBM),
-            "hevc" => Some(Self::HEVC),
-            _ => None,
-        }
-    }
-}
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = command!()
+    let command = command!()
         .about("Simple program to parse files to the most efficient formats within a set size")
         .arg(
-            arg!(-s --size <NUMBER> "Target megabyte size. If not set, default of 25mb (Discords free limit)")
+            arg!(-s --size <SIZE> "Target size, eg. `500KB`, `9.5MB`, `10MiB`, `2GB` (plain numbers are MB) or a preset: `discord` (10MB), `discord-nitro` (500MB), `telegram` (2GB), `whatsapp` (16MB), `email` (25MB). If not set, default of 25MB")
             .required(false)
-            .default_value("25")
-            .value_parser(value_parser!(u16))
+            .default_value("25MB")
+            .value_parser(TargetSize::from_str)
             )
         .arg(
-            arg!(-c --codec <CODEC> "Choose video codec between `HEVC` (H.265) and `WEBM` (vp9).")
+            arg!(-c --codec <CODEC> "Choose video codec between `HEVC` (H.265), `H264` (most compatible), `WEBM` (vp9), `AV1` (AV1 .webm) and `AV1-MP4` (AV1 .mp4).")
             .required(false)
             .default_value("WEBM")
             )
+        .arg(
+            arg!(--"image-format" <FORMAT> "What still images get converted to: `WEBP`, `AVIF` or `JPEG`. PNG, JPEG, TIFF, BMP and WebP stills are encoded in-process, other images and animations always become WebP through ffmpeg")
+            .required(false)
+            .default_value("WEBP")
+            .value_parser(|s: &str| ImageFormat::from_string(s).ok_or("expected `WEBP`, `AVIF` or `JPEG`"))
+            )
         .arg(
             arg!(-f --files <FILES> "Comma separated files to convert. EG: -f=<FILE>,<FILE>")
             .required(true)
             .value_parser(value_parser!(PathBuf))
             .value_delimiter(',')
-            .num_args(1..=std::usize::MAX)
-        ).get_matches();
-    let size = args
-        .get_one::<u16>("size")
-        .expect("Default value dissapeared from rate")
-        * 8;
+            .num_args(1..=usize::MAX)
+        )
+        .arg(
+            arg!(-t --target <PLATFORM> "Platform to convert for, sets size, codecs and resolution/framerate/duration limits in one go. Built in: `discord`, `discord-nitro`, `telegram`, `whatsapp`, `email`, `twitter`. Explicit --size and --codec still win")
+            .required(false)
+            )
+        .arg(
+            arg!(--"target-config" <FILE> "TOML file with extra --target profiles, defaults to `<config dir>/nmb/targets.toml`")
+            .required(false)
+            .value_parser(value_parser!(PathBuf))
+            )
+        .arg(
+            arg!(-j --jobs <N> "How many files get converted at once, defaults to a quarter of your cpu threads")
+            .required(false)
+            .value_parser(value_parser!(u64).range(1..))
+            )
+        .arg(
+            arg!(--split "Split videos that would end up with a too low bitrate into multiple parts, each under the size limit")
+            .required(false)
+            )
+        .arg(
+            arg!(-o --"output-dir" <DIR> "Directory to write outputs to, defaults to next to each input")
+            .required(false)
+            .value_parser(value_parser!(PathBuf))
+            )
+        .arg(
+            arg!(--"output-template" <TEMPLATE> "Output file name, with `{stem}`, `{ext}`, `{size}` (target in MB) and `{part}` (`_part<n>` for split videos) filled in")
+            .required(false)
+            .default_value(DEFAULT_TEMPLATE)
+            )
+        .arg(
+            arg!(--"on-collision" <POLICY> "What to do if an output already exists: `skip`, `rename` (default) or `overwrite`. Inputs are never overwritten")
+            .required(false)
+            .default_value("rename")
+            .value_parser(|s: &str| Collision::from_string(s).ok_or("expected `skip`, `rename` or `overwrite`"))
+            )
+        .arg(
+            arg!(--"dry-run" "Only print what would be done for each file, including the ffmpeg commands, without encoding anything")
+            .required(false)
+            )
+        .arg(
+            arg!(--report <FORMAT> "Print a summary of every job to stdout once done. Only `json` for now")
+            .required(false)
+            .value_parser(["json"])
+            )
+        .arg(
+            arg!(--ffmpeg <PATH> "ffmpeg binary to use, defaults to the one on PATH")
+            .required(false)
+            .env("NMB_FFMPEG")
+            .default_value("ffmpeg")
+            .value_parser(value_parser!(PathBuf))
+            )
+        .arg(
+            arg!(--ffprobe <PATH> "ffprobe binary to use, defaults to the one on PATH")
+            .required(false)
+            .env("NMB_FFPROBE")
+            .default_value("ffprobe")
+            .value_parser(value_parser!(PathBuf))
+            )
+        .arg(
+            arg!(--progress <FORMAT> "How progress gets shown: `bars` on stderr, or `ndjson` events on stdout for scripts")
+            .required(false)
+            .default_value("bars")
+            .value_parser(["bars", "ndjson"])
+        );
+    #[cfg(feature = "libav")]
+    let command = command.arg(
+        arg!(--backend <BACKEND> "`ffmpeg` runs the ffmpeg and ffprobe binaries, `libav` encodes in-process with the libraries nmb was built against")
+        .required(false)
+        .default_value("ffmpeg")
+        .value_parser(["ffmpeg", "libav"])
+        );
+    let args = command.get_matches();
+    let size = *args
+        .get_one::<TargetSize>("size")
+        .expect("Default value dissapeared from rate");
     let files = args
         .get_many::<PathBuf>("files")
         .context("No files specified")?
@@ -73,158 +126,241 @@ async fn main() -> anyhow::Result<()> {
     let binding = "webm".to_owned();
     let codec = args.get_one::<String>("codec").unwrap_or(&binding);
     let codec = VideoCodec::from_string(codec).unwrap_or(VideoCodec::WEBM);
+    let split = args.get_flag("split");
+    let image_format = *args
+        .get_one::<ImageFormat>("image-format")
+        .expect("Default value dissapeared from image-format");
 
-    let commands: Arc<Mutex<Vec<FFMPEGCommand>>> = Arc::new(Mutex::new(vec![]));
-    {
-        let mut commands_mut = commands.try_lock().unwrap();
-        for file in files {
-            let mut command: FFMPEGCommand;
-            let extension = file
-                .extension()
-                .context("File doesn't have extension - is folder or is invalid file")?;
-
-            match extension
-                .to_str()
-                .expect("Somehow Extension contains charcters we can't decode lol")
-                .to_lowercase()
-                .as_str()
-            {
-                "webm" | "mp4" | "mov" | "avi" | "mpeg" | "mkv" => {
-                    command =
-                        FFMPEGCommand::new(MediaType::Video, file, size, codec.clone()).await?;
-                }
-                "mp3" | "wav" | "ogg" | "opus" | "flac" | "aiff" => {
-                    command =
-                        FFMPEGCommand::new(MediaType::Audio, file, size, codec.clone()).await?;
-                }
-                "jpg" | "png" | "webp" | "exr" | "jpeg" | "tiff" | "bpm" | "raw" | "tif" => {
-                    command =
-                        FFMPEGCommand::new(MediaType::Image, file, size, codec.clone()).await?;
-                }
-                "gif" => {
-                    command =
-                        FFMPEGCommand::new(MediaType::AnimatedImage, file, size, codec.clone())
-                            .await?;
-                }
-                _ => break,
+    let constraints = match args.get_one::<String>("target") {
+        Some(name) => {
+            let targets = load_targets(
+                args.get_one::<PathBuf>("target-config")
+                    .map(|p| p.as_path()),
+            )?;
+            let mut constraints = find_target(targets, name)?.constraints(split);
+            if args.value_source("size") == Some(ValueSource::CommandLine) {
+                constraints.size = size;
             }
-
-            command.command.0.stdout(Stdio::piped());
-            command.command.0.stderr(Stdio::piped());
-            command.command.0.stdin(Stdio::null());
-            if command.media_type == MediaType::Video {
-                let mut pass2 = command.command.1.unwrap();
-                pass2.stdout(Stdio::piped());
-                pass2.stderr(Stdio::piped());
-                pass2.stdin(Stdio::null());
-                command.command.1 = Some(pass2)
+            if args.value_source("codec") == Some(ValueSource::CommandLine) {
+                constraints.codec = codec;
+                constraints.audio_codec = None;
             }
-
-            command.exec_handle = Some(command.command.0.spawn()?);
-            command.buff_reader = Some(
-                BufReader::new(
-                    command
-                        .exec_handle
-                        .as_mut()
-                        .unwrap()
-                        .stdout
-                        .take()
-                        .expect("encoder stdout missing - exited early or unavailable"),
-                )
-                .lines(),
-            );
-            commands_mut.push(command);
+            constraints.image_format = image_format;
+            constraints
         }
-    }
-    let mut command_spawns = vec![];
-    let mut buff_readers = vec![];
+        None => Constraints {
+            size,
+            codec,
+            audio_codec: None,
+            max_height: None,
+            max_fps: None,
+            max_duration: None,
+            split,
+            image_format,
+        },
+    };
 
-    let ui = tokio::spawn(display(commands.clone()));
+    let ffmpeg = Ffmpeg::new(
+        args.get_one::<PathBuf>("ffmpeg")
+            .expect("Default value dissapeared from ffmpeg")
+            .clone(),
+        args.get_one::<PathBuf>("ffprobe")
+            .expect("Default value dissapeared from ffprobe")
+            .clone(),
+    );
+    #[cfg(feature = "libav")]
+    if args.get_one::<String>("backend").map(|b| b.as_str()) == Some("libav") {
+        let libav = n_mb::backend::libav::Libav::new()?;
+        let capabilities = libav.capabilities();
+        return convert(
+            Arc::new(libav),
+            Ok(capabilities),
+            &ffmpeg,
+            &args,
+            files,
+            constraints,
+        )
+        .await;
+    }
+    let capabilities = ffmpeg.capabilities().await;
+    if !ffmpeg.has_ffprobe().await {
+        eprintln!("ffprobe not found, reading files with nmb's own probe instead");
+    }
+    convert(
+        Arc::new(ffmpeg.clone()),
+        capabilities,
+        &ffmpeg,
+        &args,
+        files,
+        constraints,
+    )
+    .await
+}
 
-    {
-        for (i, command) in commands.lock().await.iter_mut().enumerate() {
-            buff_readers.push((i, command.buff_reader.take().unwrap()));
+/// Plans every file and runs the jobs on `backend`. `ffmpeg` is only for the commands
+/// `--dry-run` prints. Not having `capabilities` only matters if a job needs ffmpeg.
+async fn convert<B: Backend>(
+    backend: Arc<B>,
+    capabilities: anyhow::Result<Capabilities>,
+    ffmpeg: &Ffmpeg,
+    args: &ArgMatches,
+    files: Vec<&PathBuf>,
+    mut constraints: Constraints,
+) -> anyhow::Result<()> {
+    let capabilities = capabilities.and_then(|capabilities| {
+        if let Some(note) = capabilities.check(&mut constraints)? {
+            eprintln!("{note}");
         }
-    }
-    for mut buff_reader in buff_readers.into_iter() {
-        use std::time::Duration;
-        use tokio::time::interval;
-        let commands_ref = commands.clone();
-        let mut intv = interval(Duration::from_millis(10));
+        Ok(capabilities)
+    });
 
-        command_spawns.push(tokio::spawn(async move {
-            intv.tick().await;
+    // two-pass logs go in here, it's removed with everything in it once nmb exits
+    let passlog_dir = tempfile::Builder::new()
+        .prefix("nmb-")
+        .tempdir()
+        .context("Can't create a temp dir for pass logs")?;
+    let mut naming = OutputNaming::new(
+        args.get_one::<PathBuf>("output-dir").cloned(),
+        args.get_one::<String>("output-template")
+            .expect("Default value dissapeared from output-template")
+            .clone(),
+        *args
+            .get_one::<Collision>("on-collision")
+            .expect("Default value dissapeared from on-collision"),
+        &files,
+        passlog_dir.path().to_path_buf(),
+    )?;
 
-            'line: while let Ok(Some(line)) = buff_reader.1.next_line().await {
-                #[cfg(debug_assertions)]
-                dbg!(&line);
-                if let Some(time_start) = line.find("out_time=") {
-                    let time: Vec<String> = line[time_start + 10..]
-                        .split(':')
-                        .map(|s| s.to_owned())
-                        .collect();
+    let mut jobs = vec![];
+    for file in files {
+        let probed = backend.probe(file).await.and_then(|probe| {
+            let media_type = probe.media_type(media_type_hint(file))?;
+            Ok((probe, media_type))
+        });
+        let (probe, media_type) = match probed {
+            Ok(probed) => probed,
+            Err(e) => {
+                eprintln!("Skipping {}: {e:#}", file.display());
+                continue;
+            }
+        };
 
-                    let mut parsed_time = vec![];
+        let planned = match (media_type, constraints.split) {
+            (MediaType::Video, true) => {
+                let keyframes = match Job::needs_split(&probe, &constraints) {
+                    true => backend.keyframes(file).await.unwrap_or_default(),
+                    false => vec![],
+                };
+                Job::plan_split(file, &probe, &keyframes, &constraints, &mut naming)
+            }
+            (media_type, _) => Job::plan(media_type, file, &probe, &constraints, &mut naming)
+                .map(|job| job.into_iter().collect()),
+        };
+        let new_jobs = match planned {
+            Ok(new_jobs) => new_jobs,
+            Err(e) => {
+                eprintln!("Skipping {}: {e:#}", file.display());
+                continue;
+            }
+        };
 
-                    for part in time {
-                        if let Ok(number) = part.parse::<f32>() {
-                            parsed_time.push(number)
-                        } else {
-                            // parsed_time.push(0.);
-                            // break 'line;
-                        }
-                    }
-                    if parsed_time.len() == 0 {
-                        parsed_time.append(&mut vec![0., 0., 0.]);
-                    }
-                    let time = parsed_time[0] * 3600. + parsed_time[1] * 60. + parsed_time[2];
+        if new_jobs.is_empty() {
+            eprintln!("Skipping {}: output already exists", file.display());
+        }
+        jobs.extend(new_jobs);
+    }
+    // a missing encoder should stop nmb here, not halfway through the jobs
+    match capabilities {
+        Ok(capabilities) => capabilities.check_jobs(&jobs)?,
+        // stills nmb encodes in-process are all it can do without a working ffmpeg
+        Err(e) if jobs.iter().any(|j| j.still().is_none()) => return Err(e),
+        Err(_) => {}
+    }
 
-                    let mut command = commands_ref.lock().await;
-                    let command = command.get_mut(buff_reader.0).unwrap();
+    let parallel = args.get_one::<u64>("jobs").map_or_else(
+        || {
+            let cpus = std::thread::available_parallelism().map_or(4, |n| n.get());
+            (cpus / 4).max(1)
+        },
+        |j| *j as usize,
+    );
+    let executor = Executor::new(backend.clone(), jobs, parallel);
+    if args.get_flag("dry-run") {
+        return print_plan(executor.jobs(), ffmpeg);
+    }
+    naming.create_dir()?;
 
-                    command.status = EncodingStatus::InProgress;
-                    command.progressed_time = time;
-                }
-                if let Some(progress_i) = line.find("progress=") {
-                    #[cfg(debug_assertions)]
-                    println!("found progress!, {}", &line[progress_i + 9..]);
-                    let mut command = commands_ref.lock().await;
-                    let command = command.get_mut(buff_reader.0).unwrap();
+    let report = args.get_one::<String>("report").is_some();
+    let bars = match args.get_one::<String>("progress").map(|p| p.as_str()) {
+        Some("ndjson") => None,
+        _ => Some(Bars::new(executor.jobs())),
+    };
 
-                    match &line[progress_i + 9..] {
-                        "end" => match command.media_type {
-                            //Executes 2nd pass
-                            MediaType::Video => match command.passed_pass_1 {
-                                true => command.status = EncodingStatus::Finished,
-                                false => {
-                                    command.exec_handle =
-                                        Some(command.command.1.as_mut().unwrap().spawn().unwrap());
-                                    buff_reader = (
-                                    buff_reader.0,
-                                    BufReader::new(
-                                        command.exec_handle.as_mut().unwrap().stdout.take().expect(
-                                            "encoder stdout missing - exited early or unavailable",
-                                        ),
-                                    )
-                                    .lines(),
-                                );
-                                    command.passed_pass_1 = true;
-                                }
-                            },
-                            _ => {
-                                command.status = EncodingStatus::Finished;
-                            }
-                        },
-                        "continue" => command.status = EncodingStatus::InProgress,
-                        _ => (),
-                    };
-                }
+    let mut run = executor.start();
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut interrupted = false;
+    loop {
+        tokio::select! {
+            event = run.next_event() => match event {
+                Some(event) => match &bars {
+                    Some(bars) => bars.update(&event),
+                    None => print_event(&event),
+                },
+                None => break,
+            },
+            _ = &mut ctrl_c, if !interrupted => {
+                interrupted = true;
+                run.cancel();
             }
-        }));
+        }
+    }
+    let results = run.results().await?;
+
+    if report {
+        print_report(&results);
+    }
+    if interrupted {
+        print_interrupted(&results);
+        drop(passlog_dir);
+        std::process::exit(130);
     }
-    for spawn in command_spawns {
-        spawn.await?;
+    let failed = results
+        .iter()
+        .filter_map(|r| match &r.status {
+            JobStatus::Failed(reason) => Some((r, reason)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        eprintln!("\n{} of {} jobs failed:", failed.len(), results.len());
+        for (result, reason) in failed {
+            eprintln!("  {}: {reason}", result.job.label());
+            for line in &result.stderr_tail {
+                eprintln!("    | {line}");
+            }
+        }
+        drop(passlog_dir);
+        std::process::exit(1);
     }
-    ui.await?;
     Ok(())
 }
+
+fn print_interrupted(results: &[JobResult]) {
+    let finished = results
+        .iter()
+        .filter(|r| r.status == JobStatus::Finished)
+        .collect::<Vec<_>>();
+    eprintln!(
+        "\nInterrupted, removed what unfinished jobs wrote so far. {} of {} jobs finished before that:",
+        finished.len(),
+        results.len()
+    );
+    for result in finished {
+        eprintln!(
+            "  {} -> {}",
+            result.job.label(),
+            result.job.output().display()
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use anyhow::{bail, Context};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use crate::size::TargetSize;
+
+pub const DEFAULT_TEMPLATE: &str = "minified_{stem}{part}.{ext}";
+
+/// What to do when an output path is already taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Collision {
+    Skip,
+    Rename,
+    Overwrite,
+}
+
+impl Collision {
+    pub fn from_string(string: &str) -> Option<Self> {
+        match string.to_lowercase().as_str() {
+            "skip" => Some(Self::Skip),
+            "rename" => Some(Self::Rename),
+            "overwrite" => Some(Self::Overwrite),
+            _ => None,
+        }
+    }
+}
+
+/// Where planned jobs write to. Planning itself never looks at the filesystem, whatever
+/// implements this decides how much it does.
+pub trait Naming {
+    /// Output path for `input`, or `None` if the job should be skipped. `part` is set for
+    /// each part of a video that gets split.
+    fn output_path(
+        &mut self,
+        input: &Path,
+        ext: &str,
+        size: TargetSize,
+        part: Option<u16>,
+    ) -> anyhow::Result<Option<PathBuf>>;
+
+    /// Two-pass log base name for the job writing `output`
+    fn passlog_path(&mut self, output: &Path) -> PathBuf;
+}
+
+/// nmb's own [`Naming`], from a template and a collision policy. Inputs of the run are never
+/// handed out as an output, whatever the policy says, and no two jobs get the same output.
+pub struct OutputNaming {
+    dir: Option<PathBuf>,
+    template: String,
+    on_collision: Collision,
+    inputs: HashSet<PathBuf>,
+    claimed: HashSet<PathBuf>,
+    passlog_dir: PathBuf,
+    passlogs: u32,
+}
+
+impl OutputNaming {
+    /// `template` supports `{stem}`, `{ext}`, `{size}` (in MB) and `{part}` (`_part<n>` when
+    /// a video gets split, empty otherwise)
+    pub fn new(
+        dir: Option<PathBuf>,
+        template: String,
+        on_collision: Collision,
+        inputs: &[&PathBuf],
+        passlog_dir: PathBuf,
+    ) -> anyhow::Result<Self> {
+        if !template.contains("{ext}") {
+            bail!("Output template `{template}` is missing `{{ext}}`");
+        }
+        Ok(OutputNaming {
+            dir,
+            template,
+            on_collision,
+            inputs: inputs.iter().map(|i| normalize(i)).collect(),
+            claimed: HashSet::new(),
+            passlog_dir,
+            passlogs: 0,
+        })
+    }
+
+    /// Creates the output dir if there is one, so ffmpeg can write into it
+    pub fn create_dir(&self) -> anyhow::Result<()> {
+        if let Some(dir) = &self.dir {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Can't create output dir {}", dir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Naming for OutputNaming {
+    /// Two-pass log base name for the job writing `output`, inside the runs private temp
+    /// dir rather than next to the files
+    fn passlog_path(&mut self, output: &Path) -> PathBuf {
+        self.passlogs += 1;
+        // outputs in different dirs can share a name, the counter keeps the logs apart
+        let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+        self.passlog_dir.join(format!("{}_{stem}", self.passlogs))
+    }
+
+    /// Renders the template, then applies the collision policy to whatever the result is
+    /// taken by
+    fn output_path(
+        &mut self,
+        input: &Path,
+        ext: &str,
+        size: TargetSize,
+        part: Option<u16>,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let stem = input
+            .file_stem()
+            .context("File has no name")?
+            .to_string_lossy();
+        let part = part.map(|p| format!("_part{p}")).unwrap_or_default();
+        let stem = match self.template.contains("{part}") {
+            true => stem.into_owned(),
+            false => stem.into_owned() + &part,
+        };
+        let file_name = self
+            .template
+            .replace("{stem}", &stem)
+            .replace("{part}", &part)
+            .replace("{size}", &(size.bytes() as f64 / 1_000_000.).to_string())
+            .replace("{ext}", ext);
+
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => input.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+        let mut path = dir.join(&file_name);
+
+        let is_input = |p: &Path| self.inputs.contains(&normalize(p));
+        let is_claimed = |p: &Path| self.claimed.contains(&normalize(p));
+        let taken = |p: &Path| is_input(p) || is_claimed(p) || p.exists();
+        if taken(&path) {
+            match self.on_collision {
+                // another job of this run getting there first is no reason to skip
+                Collision::Skip if !is_claimed(&path) => return Ok(None),
+                Collision::Overwrite if !is_input(&path) && !is_claimed(&path) => (),
+                Collision::Skip | Collision::Rename | Collision::Overwrite => {
+                    // the counter goes right before the extension, wherever the template put it
+                    let rendered = path.clone();
+                    let file_stem = rendered.file_stem().unwrap().to_string_lossy();
+                    let extension = rendered
+                        .extension()
+                        .map(|e| format!(".{}", e.to_string_lossy()))
+                        .unwrap_or_default();
+                    let mut n = 1;
+                    while taken(&path) {
+                        path = rendered.with_file_name(format!("{file_stem}-{n}{extension}"));
+                        n += 1;
+                    }
+                }
+            }
+        }
+        self.claimed.insert(normalize(&path));
+        Ok(Some(path))
+    }
+}
+
+/// Absolute version of `path`, so the same file matches no matter how it was passed in
+fn normalize(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    // doesn't exist yet, canonicalize what does
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = match parent.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => parent,
+            };
+            parent
+                .canonicalize()
+                .map(|p| p.join(name))
+                .unwrap_or_else(|_| path.to_path_buf())
+        }
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const SIZE: TargetSize = TargetSize::from_bytes(10_000_000);
+
+    /// Naming for outputs next to `inputs`, which get created in a fresh dir
+    fn new_naming(
+        template: &str,
+        on_collision: Collision,
+        inputs: &[&str],
+    ) -> (TempDir, OutputNaming) {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = inputs
+            .iter()
+            .map(|name| {
+                let path = dir.path().join(name);
+                std::fs::write(&path, b"").unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        let naming = OutputNaming::new(
+            None,
+            template.into(),
+            on_collision,
+            &inputs.iter().collect::<Vec<_>>(),
+            dir.path().to_path_buf(),
+        )
+        .unwrap();
+        (dir, naming)
+    }
+
+    fn name(path: Option<PathBuf>) -> String {
+        path.unwrap()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn overwrite_never_replaces_an_input() {
+        let (dir, mut naming) = new_naming("{stem}.{ext}", Collision::Overwrite, &["a.webm"]);
+        let output = naming.output_path(&dir.path().join("a.webm"), "webm", SIZE, None);
+        assert_eq!(name(output.unwrap()), "a-1.webm");
+
+        // anything else that's in the way does get replaced
+        std::fs::write(dir.path().join("b.webm"), b"").unwrap();
+        let output = naming.output_path(&dir.path().join("b.mkv"), "webm", SIZE, None);
+        assert_eq!(name(output.unwrap()), "b.webm");
+    }
+
+    #[test]
+    fn skip_only_skips_files_from_before_the_run() {
+        let (dir, mut naming) = new_naming(DEFAULT_TEMPLATE, Collision::Skip, &["a.mp4", "a.mkv"]);
+        let first = naming.output_path(&dir.path().join("a.mp4"), "webm", SIZE, None);
+        assert_eq!(name(first.unwrap()), "minified_a.webm");
+        // claimed by the job before it, not an existing file
+        let second = naming.output_path(&dir.path().join("a.mkv"), "webm", SIZE, None);
+        assert_eq!(name(second.unwrap()), "minified_a-1.webm");
+
+        std::fs::write(dir.path().join("minified_b.webm"), b"").unwrap();
+        let existing = naming.output_path(&dir.path().join("b.mp4"), "webm", SIZE, None);
+        assert_eq!(existing.unwrap(), None);
+    }
+
+    #[test]
+    fn renames_count_up_past_taken_names() {
+        let (dir, mut naming) = new_naming(DEFAULT_TEMPLATE, Collision::Rename, &["a.mp4"]);
+        std::fs::write(dir.path().join("minified_a.webm"), b"").unwrap();
+        let input = dir.path().join("a.mp4");
+        let names = (0..3)
+            .map(|_| name(naming.output_path(&input, "webm", SIZE, None).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "minified_a-1.webm",
+                "minified_a-2.webm",
+                "minified_a-3.webm"
+            ]
+        );
+    }
+
+    #[test]
+    fn parts_are_added_to_templates_without_them() {
+        let (dir, mut naming) = new_naming("{stem}_{size}MB.{ext}", Collision::Rename, &["a.mp4"]);
+        let output = naming.output_path(&dir.path().join("a.mp4"), "webm", SIZE, Some(2));
+        assert_eq!(name(output.unwrap()), "a_part2_10MB.webm");
+
+        let (dir, mut naming) = new_naming(DEFAULT_TEMPLATE, Collision::Rename, &["a.mp4"]);
+        let output = naming.output_path(&dir.path().join("a.mp4"), "webm", SIZE, Some(2));
+        assert_eq!(name(output.unwrap()), "minified_a_part2.webm");
+    }
+
+    #[test]
+    fn renames_keep_the_templates_dir_and_text() {
+        let (dir, mut naming) =
+            new_naming("previews/{stem}.min.{ext}", Collision::Rename, &["a.mp4"]);
+        std::fs::create_dir(dir.path().join("previews")).unwrap();
+        std::fs::write(dir.path().join("previews/a.min.webm"), b"").unwrap();
+        let output = naming.output_path(&dir.path().join("a.mp4"), "webm", SIZE, None);
+        assert_eq!(
+            output.unwrap(),
+            Some(dir.path().join("previews/a.min-1.webm"))
+        );
+    }
+
+    #[test]
+    fn inputs_match_however_their_path_is_written() {
+        let (dir, mut naming) = new_naming("{stem}.{ext}", Collision::Overwrite, &["a.webm"]);
+        let roundabout = dir.path().join(".").join("sub").join("..").join("a.webm");
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        let output = naming.output_path(&roundabout, "webm", SIZE, None);
+        assert_eq!(name(output.unwrap()), "a-1.webm");
+    }
+}
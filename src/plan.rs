@@ -0,0 +1,818 @@
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::bitrate::{audio_bitrate, plan_video, video_bitrates, VideoPlan, VIDEO_SHARE};
+use crate::output::Naming;
+use crate::probe::Probe;
+use crate::profile::{to_args, PassSettings};
+use crate::size::TargetSize;
+use crate::still::{self, Still};
+use crate::{AudioCodec, ImageFormat, VideoCodec};
+/// How many times pass 2 gets run in total before giving up on fitting under the target size
+pub const MAX_SIZE_ATTEMPTS: u8 = 3;
+/// Extra headroom taken off the bitrate when re-encoding an oversized output
+const OVERSHOOT_MARGIN: f32 = 0.97;
+const DEFAULT_THREADS: u16 = 16;
+/// With `--split`, videos get cut into parts rather than going under this bitrate
+pub const SPLIT_MIN_VIDEO_BITRATE: f32 = 800.; //kbits
+const MAX_IMAGE_QUALITY: u8 = 90;
+const MIN_IMAGE_QUALITY: u8 = 10;
+/// Factor the image dimensions get multiplied by once no quality fits anymore
+const IMAGE_DOWNSCALE_STEP: f32 = 0.75;
+const MIN_IMAGE_SCALE: f32 = 0.1;
+/// Animated images keep at most every n-th frame when lowering the framerate
+const MAX_FRAME_STEP: u8 = 4;
+
+/// Everything decided about converting one file (or one part of it), before anything runs.
+/// Planning only looks at the probe and the constraints, running the job is up to
+/// [`crate::executor::Executor`].
+pub struct Job {
+    label: String,
+    input: PathBuf,
+    /// Where the final file ends up
+    output: PathBuf,
+    /// What ffmpeg actually writes to, renamed to `output` once it checks out
+    partial: PathBuf,
+    /// Encoders doing the work, eg. `libvpx-vp9+libopus`
+    codec: String,
+    target_size: TargetSize,
+    duration: Option<f32>,
+    media_type: MediaType,
+    encode: Encode,
+    size_attempt: u8,
+}
+
+/// How a job gets encoded, with the settings that change between size attempts
+enum Encode {
+    Audio { codec: AudioCodec, bitrate: f32 },
+    Video(VideoPasses),
+    Image(ImageSearch),
+}
+
+/// Limits a job has to stay within, from `--size`/`--codec` or a `--target` profile
+#[derive(Debug, Clone)]
+pub struct Constraints {
+    pub size: TargetSize,
+    pub codec: VideoCodec,
+    /// Replaces the audio codec the video codec would use otherwise
+    pub audio_codec: Option<AudioCodec>,
+    pub max_height: Option<u16>,
+    pub max_fps: Option<f32>,
+    pub max_duration: Option<f32>, //secs
+    pub split: bool,
+    /// What still images nmb decodes itself get written as, the rest become WebP
+    pub image_format: ImageFormat,
+}
+
+/// Everything needed to (re)build the two ffmpeg passes of a video encode
+struct VideoPasses {
+    input: PathBuf,
+    output: PathBuf,
+    passlogfile: PathBuf,
+    codec: VideoCodec,
+    audio_codec: Option<AudioCodec>,
+    height: u16,
+    /// Framerate cap, only set if the source goes over it
+    fps: Option<f32>,
+    video_bitrate: f32,
+    audio_bitrate: f32,
+    threads: u16,
+    segment: Option<Segment>,
+}
+
+/// Part of a video that gets encoded as its own file
+struct Segment {
+    start: f32,
+    duration: f32,
+    part: u16,
+    parts: u16,
+}
+
+/// Where the size search of an image job is at
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ImageSettings {
+    pub quality: u8,
+    pub scale: f32,
+    /// Resolution after scaling, if the source resolution is known
+    pub resolution: Option<(u16, u16)>,
+    /// Only every n-th frame is kept
+    pub frame_step: u8,
+}
+
+/// What planning a video needs to know about its source
+struct VideoSource {
+    duration: f32,
+    resolution: (u16, u16),
+    fps: Option<f32>,
+}
+
+impl VideoSource {
+    fn from_probe(probe: &Probe) -> anyhow::Result<Self> {
+        let stream = probe.video().context("No video stream")?;
+        Ok(VideoSource {
+            duration: probe
+                .duration()
+                .context("can't find duration of media anywhere")?,
+            resolution: stream.resolution().context("Missing resolution")?,
+            fps: stream.fps(),
+        })
+    }
+}
+
+impl Job {
+    /// Plans the job for `path`, without touching it or the filesystem. `None` means
+    /// `naming` had it skipped.
+    pub fn plan(
+        media_type: MediaType,
+        path: &Path,
+        probe: &Probe,
+        constraints: &Constraints,
+        naming: &mut impl Naming,
+    ) -> anyhow::Result<Option<Self>> {
+        match media_type {
+            MediaType::Video => Self::plan_video(path, probe, constraints, naming),
+            MediaType::Audio => Self::plan_audio(path, probe, constraints, naming),
+            MediaType::Image => Self::plan_image(path, probe, constraints, false, naming),
+            MediaType::AnimatedImage => Self::plan_image(path, probe, constraints, true, naming),
+        }
+    }
+
+    fn plan_audio(
+        path: &Path,
+        probe: &Probe,
+        constraints: &Constraints,
+        naming: &mut impl Naming,
+    ) -> anyhow::Result<Option<Self>> {
+        let size = constraints.size;
+        let audio_codec = constraints.audio_codec.unwrap_or(AudioCodec::OPUS);
+        let duration = probe
+            .duration()
+            .context("can't find duration of media anywhere")?;
+        let bitrate = audio_bitrate(size, duration, probe.audio_kbit_rate())?;
+        let Some(new_path) = naming.output_path(path, audio_codec.extension(), size, None)? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Job {
+            label: file_name(path),
+            input: path.to_path_buf(),
+            duration: Some(duration),
+            partial: partial_path(&new_path),
+            output: new_path,
+            codec: audio_codec.encoder().to_owned(),
+            media_type: MediaType::Audio,
+            target_size: size,
+            encode: Encode::Audio {
+                codec: audio_codec,
+                bitrate,
+            },
+            size_attempt: 1,
+        }))
+    }
+
+    fn plan_video(
+        path: &Path,
+        probe: &Probe,
+        constraints: &Constraints,
+        naming: &mut impl Naming,
+    ) -> anyhow::Result<Option<Self>> {
+        let source = VideoSource::from_probe(probe)?;
+        // without --split, anything over the platforms length limit gets cut off
+        let segment = match constraints.max_duration {
+            Some(max) if source.duration > max => Some(Segment {
+                start: 0.,
+                duration: max,
+                part: 1,
+                parts: 1,
+            }),
+            _ => None,
+        };
+        Self::plan_video_segment(path, constraints, &source, segment, naming)
+    }
+
+    /// Whether [`Job::plan_split`] would cut the video into parts, so its keyframes are only
+    /// looked up when they're needed
+    pub fn needs_split(probe: &Probe, constraints: &Constraints) -> bool {
+        let Some(duration) = probe.duration() else {
+            return false;
+        };
+        // a video too long to fit at all might still fit in parts
+        video_bitrates(constraints.size, duration).map_or(true, |(video_bitrate, _)| {
+            video_bitrate < SPLIT_MIN_VIDEO_BITRATE
+        }) || constraints.max_duration.is_some_and(|max| duration > max)
+    }
+
+    /// Like a normal video job, but if fitting the whole video under the size would push the
+    /// video bitrate under [`SPLIT_MIN_VIDEO_BITRATE`] (or it's longer than the targets max
+    /// duration), it gets cut into parts that each fit on their own. Cuts land on the
+    /// `keyframes` (in seconds, see [`crate::backend::Backend::keyframes`]) closest to even parts.
+    pub fn plan_split(
+        path: &Path,
+        probe: &Probe,
+        keyframes: &[f32],
+        constraints: &Constraints,
+        naming: &mut impl Naming,
+    ) -> anyhow::Result<Vec<Self>> {
+        let source = VideoSource::from_probe(probe)?;
+        let duration = source.duration;
+
+        if !Self::needs_split(probe, constraints) {
+            let job = Self::plan_video_segment(path, constraints, &source, None, naming)?;
+            return Ok(job.into_iter().collect());
+        }
+
+        // longest part that still gets the minimum bitrate and the platform accepts
+        let mut max_part_duration =
+            constraints.size.kbits() * VIDEO_SHARE / SPLIT_MIN_VIDEO_BITRATE;
+        if let Some(max) = constraints.max_duration {
+            max_part_duration = max_part_duration.min(max);
+        }
+
+        let mut boundaries = vec![0.];
+        let mut last = 0.;
+        while duration - last > max_part_duration {
+            let remaining_parts = ((duration - last) / max_part_duration).ceil();
+            let ideal = last + (duration - last) / remaining_parts;
+            let cut = keyframes
+                .iter()
+                .copied()
+                .filter(|k| *k > last && *k - last <= max_part_duration)
+                .min_by(|a, b| (a - ideal).abs().total_cmp(&(b - ideal).abs()))
+                .unwrap_or(ideal);
+            boundaries.push(cut);
+            last = cut;
+        }
+        boundaries.push(duration);
+
+        let parts = boundaries.len() as u16 - 1;
+        let mut jobs = vec![];
+        for (i, bounds) in boundaries.windows(2).enumerate() {
+            let segment = Segment {
+                start: bounds[0],
+                duration: bounds[1] - bounds[0],
+                part: i as u16 + 1,
+                parts,
+            };
+            jobs.extend(Self::plan_video_segment(
+                path,
+                constraints,
+                &source,
+                Some(segment),
+                naming,
+            )?);
+        }
+        Ok(jobs)
+    }
+
+    fn plan_video_segment(
+        path: &Path,
+        constraints: &Constraints,
+        source: &VideoSource,
+        segment: Option<Segment>,
+        naming: &mut impl Naming,
+    ) -> anyhow::Result<Option<Self>> {
+        let size = constraints.size;
+        let codec = constraints.codec.clone();
+        let profile = codec.profile();
+        let duration = segment.as_ref().map_or(source.duration, |s| s.duration);
+        let VideoPlan {
+            mut video_bitrate,
+            audio_bitrate,
+            mut height,
+        } = plan_video(duration, source.resolution.1, constraints)?;
+        let mut fps = constraints
+            .max_fps
+            .filter(|max| source.fps.is_none_or(|fps| fps > *max));
+        // smaller frames first, then fewer of them, then a lower bitrate
+        if let Some(level) = profile.level() {
+            let (width, source_height) = source.resolution;
+            let aspect = width as f32 / source_height.max(1) as f32;
+            height = level.fit_height(height, aspect);
+            let max_fps = level.max_fps(aspect, height);
+            if fps.or(source.fps).is_none_or(|fps| fps > max_fps) {
+                fps = Some(max_fps);
+            }
+            video_bitrate = video_bitrate.min(level.max_bitrate);
+        }
+
+        let part = segment.as_ref().filter(|s| s.parts > 1).map(|s| s.part);
+        let Some(new_path) = naming.output_path(path, profile.extension(), size, part)? else {
+            return Ok(None);
+        };
+        let passlogfile = naming.passlog_path(&new_path);
+        let mut label = file_name(path);
+        match &segment {
+            Some(segment) if segment.parts > 1 => {
+                label += &format!(" (part {}/{})", segment.part, segment.parts);
+            }
+            Some(segment) => label += &format!(" (trimmed to {}s)", segment.duration),
+            None => (),
+        }
+        let encoders = format!(
+            "{}+{}",
+            profile.video_encoder(),
+            constraints
+                .audio_codec
+                .unwrap_or(profile.audio_codec())
+                .encoder()
+        );
+        let passes = VideoPasses {
+            input: path.to_path_buf(),
+            output: partial_path(&new_path),
+            passlogfile,
+            codec,
+            audio_codec: constraints.audio_codec,
+            height,
+            fps,
+            video_bitrate,
+            audio_bitrate,
+            threads: DEFAULT_THREADS,
+            segment,
+        };
+        Ok(Some(Job {
+            label,
+            input: path.to_path_buf(),
+            duration: Some(duration),
+            output: new_path,
+            codec: encoders,
+            partial: passes.output.clone(),
+            media_type: MediaType::Video,
+            target_size: size,
+            encode: Encode::Video(passes),
+            size_attempt: 1,
+        }))
+    }
+
+    /// Images and animated images get converted to WebP by ffmpeg, the animated ones looping
+    /// forever and keeping the original frame timestamps. Still images nmb can decode itself
+    /// are encoded in-process instead, to the format the constraints ask for.
+    fn plan_image(
+        path: &Path,
+        probe: &Probe,
+        constraints: &Constraints,
+        animated: bool,
+        naming: &mut impl Naming,
+    ) -> anyhow::Result<Option<Self>> {
+        let size = constraints.size;
+        let video = probe.video();
+        let format = video
+            .filter(|v| !animated && still::decodes(&v.codec_name))
+            .map(|_| constraints.image_format);
+        let extension = format.map_or("webp", |f| f.extension());
+        let Some(new_path) = naming.output_path(path, extension, size, None)? else {
+            return Ok(None);
+        };
+        let resolution = video.and_then(|s| s.resolution());
+        let search = ImageSearch::new(
+            path.to_path_buf(),
+            partial_path(&new_path),
+            resolution,
+            animated,
+            format,
+        );
+        Ok(Some(Job {
+            label: file_name(path),
+            input: path.to_path_buf(),
+            duration: animated.then(|| probe.duration()).flatten(),
+            output: new_path,
+            codec: format.map_or("libwebp", |f| f.encoder()).to_owned(),
+            partial: search.output.clone(),
+            media_type: match animated {
+                true => MediaType::AnimatedImage,
+                false => MediaType::Image,
+            },
+            target_size: size,
+            encode: Encode::Image(search),
+            size_attempt: 1,
+        }))
+    }
+
+    /// Input file name, plus which part of it this is for split or trimmed videos
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn input(&self) -> &Path {
+        &self.input
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+
+    /// Hidden file the encode gets written to before it's moved to [`Job::output`]
+    pub fn partial(&self) -> &Path {
+        &self.partial
+    }
+
+    /// Encoders doing the work, eg. `libvpx-vp9+libopus`
+    pub fn codec(&self) -> &str {
+        &self.codec
+    }
+
+    pub fn media_type(&self) -> MediaType {
+        self.media_type
+    }
+
+    pub fn target_size(&self) -> TargetSize {
+        self.target_size
+    }
+
+    /// Length of what gets encoded in seconds, images have none
+    pub fn duration(&self) -> Option<f32> {
+        self.duration
+    }
+
+    /// Which attempt at fitting under the target size the job is at, starting at 1
+    pub fn size_attempts(&self) -> u8 {
+        self.size_attempt
+    }
+
+    /// Planned video and audio bitrate in kbits, images have neither
+    pub fn bitrates(&self) -> (Option<f32>, Option<f32>) {
+        match &self.encode {
+            Encode::Video(passes) => (Some(passes.video_bitrate), Some(passes.audio_bitrate)),
+            Encode::Audio { bitrate, .. } => (None, Some(*bitrate)),
+            Encode::Image(_) => (None, None),
+        }
+    }
+
+    /// Lowest and highest video bitrate the encoder gets told about, in kbits
+    pub fn rate_window(&self) -> (Option<f32>, Option<f32>) {
+        match &self.encode {
+            Encode::Video(passes) => passes.codec.profile().rate_window(passes.video_bitrate),
+            _ => (None, None),
+        }
+    }
+
+    /// Height videos get scaled to
+    pub fn height(&self) -> Option<u16> {
+        match &self.encode {
+            Encode::Video(passes) => Some(passes.height),
+            _ => None,
+        }
+    }
+
+    /// Codec videos get encoded with
+    pub fn video_codec(&self) -> Option<&VideoCodec> {
+        match &self.encode {
+            Encode::Video(passes) => Some(&passes.codec),
+            _ => None,
+        }
+    }
+
+    /// Framerate videos get capped to, only set if the source goes over the limit
+    pub fn fps_cap(&self) -> Option<f32> {
+        match &self.encode {
+            Encode::Video(passes) => passes.fps,
+            _ => None,
+        }
+    }
+
+    /// Settings of the current image encode attempt, `None` for anything that isn't an image
+    pub fn image_settings(&self) -> Option<ImageSettings> {
+        match &self.encode {
+            Encode::Image(search) => Some(search.settings()),
+            _ => None,
+        }
+    }
+
+    /// The current attempt of a still image nmb encodes in-process, `None` for anything ffmpeg
+    /// encodes
+    pub fn still(&self) -> Option<Still> {
+        match &self.encode {
+            Encode::Image(search) => search.still(),
+            _ => None,
+        }
+    }
+
+    /// How many ffmpeg runs one attempt takes, 2 for two-pass video and 1 for everything else
+    pub fn passes(&self) -> u8 {
+        match &self.encode {
+            Encode::Video(_) => 2,
+            _ => 1,
+        }
+    }
+
+    /// Sets how many threads the video encoder may use
+    pub fn set_threads(&mut self, threads: u16) {
+        if let Encode::Video(passes) = &mut self.encode {
+            passes.threads = threads;
+        }
+    }
+
+    /// ffmpeg arguments of `pass` (from 1 to [`Job::passes`]) for the current attempt, see
+    /// [`crate::backend::Backend::start`]
+    pub fn args(&self, pass: u8) -> anyhow::Result<Vec<String>> {
+        match &self.encode {
+            Encode::Video(passes) => passes.args(pass),
+            Encode::Audio { codec, bitrate } => {
+                audio_args(&self.input, &self.partial, *codec, *bitrate)
+            }
+            Encode::Image(search) => search.args(),
+        }
+    }
+
+    /// Feeds back the size of the finished attempt. Video jobs that overshot get their
+    /// bitrate scaled down by the measured overshoot for another pass 2, reusing the pass 1
+    /// log, and images continue their quality/scale search. Returns `true` when another attempt
+    /// is needed, errors once fitting is hopeless.
+    pub fn retry(&mut self, actual_bytes: u64) -> anyhow::Result<bool> {
+        let target_bytes = self.target_size.bytes();
+        match &mut self.encode {
+            Encode::Image(search) => {
+                if !search.advance(actual_bytes <= target_bytes)? {
+                    return Ok(false);
+                }
+            }
+            Encode::Video(passes) => {
+                if actual_bytes <= target_bytes {
+                    return Ok(false);
+                }
+                if self.size_attempt >= MAX_SIZE_ATTEMPTS {
+                    bail!(
+                        "{} is still {} bytes over the {} limit after {} attempts",
+                        self.label,
+                        actual_bytes - target_bytes,
+                        self.target_size,
+                        self.size_attempt
+                    );
+                }
+                let overshoot = actual_bytes as f32 / target_bytes as f32;
+                passes.video_bitrate = passes.video_bitrate / overshoot * OVERSHOOT_MARGIN;
+            }
+            Encode::Audio { .. } => return Ok(false),
+        }
+        self.size_attempt += 1;
+        Ok(true)
+    }
+
+    /// Two-pass logs the encoder may leave behind, nothing for jobs without two passes
+    pub fn passlogs(&self) -> Vec<PathBuf> {
+        let Encode::Video(passes) = &self.encode else {
+            return vec![];
+        };
+        // libvpx/libaom/libx264 write `<passlogfile>-0.log`, x264 adds a `.mbtree`, x265
+        // gets its own stats file plus a `.cutree`, and both keep `.temp`s while running
+        [
+            "-0.log",
+            "-0.log.temp",
+            "-0.log.mbtree",
+            "-0.log.mbtree.temp",
+            "-x265.log",
+            "-x265.log.temp",
+            "-x265.log.cutree",
+            "-x265.log.cutree.temp",
+        ]
+        .into_iter()
+        .map(|suffix| {
+            let mut log = passes.passlogfile.as_os_str().to_owned();
+            log.push(suffix);
+            log.into()
+        })
+        .collect()
+    }
+}
+
+/// Audio only jobs are a single ffmpeg run at a fixed bitrate
+fn audio_args(
+    input: &Path,
+    output: &Path,
+    codec: AudioCodec,
+    bitrate: f32,
+) -> anyhow::Result<Vec<String>> {
+    let mut args = to_args(&["-y", "-i", input.to_str().context("missing or bad path")?]);
+    args.extend(codec.args());
+    args.extend(to_args(&[
+        "-b:a",
+        &format!("{}k", bitrate as u32),
+        output.to_str().context("missing or bad path")?,
+    ]));
+    Ok(args)
+}
+
+impl VideoPasses {
+    /// ffmpeg arguments for pass `1` or `2`
+    fn args(&self, pass: u8) -> anyhow::Result<Vec<String>> {
+        let profile = self.codec.profile();
+        let old_path_str = self.input.to_str().context("missing or bad path")?;
+        // -2 keeps the width even, which the yuv420p encoders require
+        let mut filter_arg = format!("scale=-2:{}", self.height);
+        if let Some(fps) = self.fps {
+            filter_arg += &format!(",fps={fps}");
+        }
+        let audio_codec = self.audio_codec.unwrap_or(profile.audio_codec());
+        let ba_arg = format!("{}k", self.audio_bitrate as u32);
+
+        let mut args = to_args(&["-y"]);
+        if let Some(segment) = &self.segment {
+            args.extend(to_args(&["-ss", &segment.start.to_string()]));
+        }
+        args.extend(to_args(&["-i", old_path_str]));
+        if let Some(segment) = &self.segment {
+            args.extend(to_args(&["-t", &segment.duration.to_string()]));
+        }
+        args.extend(to_args(&["-vcodec", profile.video_encoder()]));
+        args.extend(audio_codec.args());
+        args.extend(to_args(&["-vf", &filter_arg, "-b:a", &ba_arg]));
+        args.extend(profile.video_args(&PassSettings {
+            pass,
+            passlogfile: &self.passlogfile,
+            video_bitrate: self.video_bitrate,
+            threads: self.threads,
+        }));
+
+        if pass == 1 {
+            // the null muxer, since mp4 can't be written to a non seekable /dev/null
+            args.extend(to_args(&["-f", "null"]));
+            if cfg!(windows) {
+                args.push("NUL".into());
+            } else {
+                args.push("/dev/null".into());
+            }
+        } else {
+            if profile.extension() == "mp4" {
+                args.extend(to_args(&["-movflags", "+faststart"]));
+            }
+            args.push(self.output.to_str().context("missing or bad path")?.into());
+        }
+        Ok(args)
+    }
+}
+
+/// Binary search over image quality, falling back to shrinking the image (and for animated
+/// images alternately dropping frames) when even the lowest quality doesn't fit under the
+/// target size
+struct ImageSearch {
+    input: PathBuf,
+    output: PathBuf,
+    resolution: Option<(u16, u16)>,
+    animated: bool,
+    quality: u8,
+    scale: f32,
+    /// Only every n-th frame is kept, the rest get merged into the previous frames duration
+    frame_step: u8,
+    /// Set if nmb encodes it in-process, see [`still`]
+    format: Option<ImageFormat>,
+    lowest: u8,
+    highest: u8,
+    best_fit: Option<u8>,
+}
+
+impl ImageSearch {
+    fn new(
+        input: PathBuf,
+        output: PathBuf,
+        resolution: Option<(u16, u16)>,
+        animated: bool,
+        format: Option<ImageFormat>,
+    ) -> Self {
+        ImageSearch {
+            input,
+            output,
+            resolution,
+            animated,
+            format,
+            quality: MAX_IMAGE_QUALITY,
+            scale: 1.,
+            frame_step: 1,
+            lowest: MIN_IMAGE_QUALITY,
+            highest: MAX_IMAGE_QUALITY,
+            best_fit: None,
+        }
+    }
+
+    fn settings(&self) -> ImageSettings {
+        ImageSettings {
+            quality: self.quality,
+            scale: self.scale,
+            resolution: self.resolution.map(|(w, h)| {
+                (
+                    (w as f32 * self.scale) as u16,
+                    (h as f32 * self.scale) as u16,
+                )
+            }),
+            frame_step: self.frame_step,
+        }
+    }
+
+    /// Feeds back whether the last encode fit. Returns `true` if another encode is needed.
+    fn advance(&mut self, fits: bool) -> anyhow::Result<bool> {
+        if fits {
+            // last encode was the final re-encode of the best fitting quality
+            if self.best_fit == Some(self.quality) {
+                return Ok(false);
+            }
+            self.best_fit = Some(self.quality);
+            self.lowest = self.quality + 1;
+        } else {
+            self.highest = self.quality.saturating_sub(1);
+        }
+
+        if self.lowest <= self.highest {
+            self.quality = (self.lowest + self.highest).div_ceil(2);
+            return Ok(true);
+        }
+        match self.best_fit {
+            Some(quality) if quality == self.quality => Ok(false),
+            Some(quality) => {
+                self.quality = quality;
+                Ok(true)
+            }
+            None => {
+                let downscales = (self.scale.ln() / IMAGE_DOWNSCALE_STEP.ln()).round() as u8;
+                if self.animated
+                    && self.frame_step < MAX_FRAME_STEP
+                    && self.frame_step <= downscales
+                {
+                    self.frame_step += 1;
+                } else {
+                    self.scale *= IMAGE_DOWNSCALE_STEP;
+                }
+                if self.scale < MIN_IMAGE_SCALE {
+                    bail!("image can't fit under the target size even when downscaled");
+                }
+                self.quality = MAX_IMAGE_QUALITY;
+                self.lowest = MIN_IMAGE_QUALITY;
+                self.highest = MAX_IMAGE_QUALITY;
+                Ok(true)
+            }
+        }
+    }
+
+    fn still(&self) -> Option<Still> {
+        Some(Still {
+            output: self.output.clone(),
+            format: self.format?,
+            quality: self.quality,
+            scale: self.scale,
+        })
+    }
+
+    fn args(&self) -> anyhow::Result<Vec<String>> {
+        if self.format.is_some() {
+            bail!("still images are encoded in-process, not by ffmpeg");
+        }
+        let mut args = to_args(&[
+            "-y",
+            "-i",
+            self.input.to_str().context("missing or bad path")?,
+        ]);
+        let mut filters = vec![];
+        if self.frame_step > 1 {
+            filters.push(format!("select='not(mod(n\\,{}))'", self.frame_step));
+        }
+        if self.scale < 1. {
+            filters.push(format!("scale=iw*{}:-1", self.scale));
+        }
+        if !filters.is_empty() {
+            args.extend(to_args(&["-vf", &filters.join(",")]));
+        }
+        if self.animated {
+            // passthrough keeps the (possibly variable) gif frame delays
+            args.extend(to_args(&[
+                "-c:v",
+                "libwebp",
+                "-loop",
+                "0",
+                "-an",
+                "-fps_mode",
+                "passthrough",
+            ]));
+        }
+        args.extend(to_args(&[
+            "-qscale",
+            &self.quality.to_string(),
+            "-compression_level",
+            "6",
+            self.output.to_str().context("missing or bad path")?,
+        ]));
+        Ok(args)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaType {
+    Video,
+    Audio,
+    Image,
+    AnimatedImage,
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map_or_else(|| path.to_string_lossy(), |n| n.to_string_lossy())
+        .into_owned()
+}
+
+/// Hidden file next to `output` that ffmpeg writes into, keeping the extension so ffmpeg
+/// still picks the right muxer
+fn partial_path(output: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(output.file_stem().unwrap_or_default());
+    name.push(".nmb-partial.");
+    name.push(output.extension().unwrap_or_default());
+    output.with_file_name(name)
+}
@@ -0,0 +1,267 @@
+use anyhow::bail;
+use serde::{Deserialize, Deserializer};
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+use crate::plan::MediaType;
+
+pub mod native;
+
+/// Everything ffprobe knows about a file, from a single `-show_streams -show_format` run. See
+/// [`crate::backend::Backend::probe`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct Probe {
+    #[serde(default)]
+    pub streams: Vec<Stream>,
+    pub format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Stream {
+    pub index: u32,
+    pub codec_type: StreamKind,
+    #[serde(default)]
+    pub codec_name: String,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub pix_fmt: Option<String>,
+    r_frame_rate: Option<String>,
+    avg_frame_rate: Option<String>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub duration: Option<f32>, //secs
+    #[serde(default, deserialize_with = "lenient")]
+    pub bit_rate: Option<u32>, //bits
+    #[serde(default, deserialize_with = "lenient")]
+    pub nb_frames: Option<u64>,
+    #[serde(default, deserialize_with = "lenient")]
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    #[serde(default)]
+    disposition: HashMap<String, u8>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    #[serde(default)]
+    side_data_list: Vec<SideData>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SideData {
+    rotation: Option<f32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Format {
+    #[serde(default)]
+    pub format_name: String,
+    #[serde(default, deserialize_with = "lenient")]
+    pub duration: Option<f32>, //secs
+    #[serde(default, deserialize_with = "lenient")]
+    pub bit_rate: Option<u32>, //bits
+    #[serde(default, deserialize_with = "lenient")]
+    pub size: Option<u64>, //bytes
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+/// ffprobe prints most numbers as strings and uses `N/A` for unknown values
+fn lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    Ok(
+        match Option::<serde_json::Value>::deserialize(deserializer)? {
+            Some(serde_json::Value::String(s)) => s.parse().ok(),
+            Some(serde_json::Value::Number(n)) => n.to_string().parse().ok(),
+            _ => None,
+        },
+    )
+}
+
+impl Stream {
+    /// Cover art of audio files shows up as a video stream too
+    pub fn is_attached_pic(&self) -> bool {
+        self.disposition.get("attached_pic") == Some(&1)
+    }
+
+    /// Degrees the player rotates the picture by, from the display matrix or the older
+    /// `rotate` tag
+    pub fn rotation(&self) -> i32 {
+        self.side_data_list
+            .iter()
+            .find_map(|d| d.rotation)
+            .map(|r| r as i32)
+            .or_else(|| self.tags.get("rotate").and_then(|r| r.parse().ok()))
+            .unwrap_or(0)
+    }
+
+    /// Width and height as displayed, ffmpeg applies the rotation before any filters
+    pub fn resolution(&self) -> Option<(u16, u16)> {
+        let (width, height) = self.width.zip(self.height)?;
+        match self.rotation().rem_euclid(180) {
+            90 => Some((height, width)),
+            _ => Some((width, height)),
+        }
+    }
+
+    pub fn fps(&self) -> Option<f32> {
+        [&self.avg_frame_rate, &self.r_frame_rate]
+            .into_iter()
+            .flatten()
+            .find_map(|rate| parse_rate(rate))
+    }
+
+    /// Matroska only keeps stream durations as a `DURATION` tag
+    fn tagged_duration(&self) -> Option<f32> {
+        self.tags
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("duration"))
+            .and_then(|(_, v)| parse_timestamp(v))
+    }
+}
+
+impl Probe {
+    /// The main picture, cover art doesn't count
+    pub fn video(&self) -> Option<&Stream> {
+        self.streams
+            .iter()
+            .find(|s| s.codec_type == StreamKind::Video && !s.is_attached_pic())
+    }
+
+    pub fn audio(&self) -> Option<&Stream> {
+        self.streams
+            .iter()
+            .find(|s| s.codec_type == StreamKind::Audio)
+    }
+
+    pub fn duration(&self) -> Option<f32> {
+        let main = self.video().or(self.audio());
+        main.and_then(|s| s.duration)
+            .or(self.format.duration)
+            .or_else(|| main.and_then(|s| s.tagged_duration()))
+            .filter(|d| *d > 0.)
+    }
+
+    /// Bitrate of the audio, falling back to the whole file for audio only files
+    pub fn audio_kbit_rate(&self) -> Option<u32> {
+        self.audio()
+            .and_then(|s| s.bit_rate)
+            .or_else(|| self.video().is_none().then_some(self.format.bit_rate)?)
+            .map(|b| b / 1000)
+    }
+
+    /// Decides what kind of media this is from its streams. `hint` is what the extension
+    /// suggests, it only matters when the streams leave it open.
+    pub fn media_type(&self, hint: Option<MediaType>) -> anyhow::Result<MediaType> {
+        let has_audio = self.audio().is_some();
+        let Some(video) = self.video() else {
+            return match has_audio {
+                true => Ok(MediaType::Audio),
+                false => bail!("no audio or video streams"),
+            };
+        };
+
+        let frames = video.nb_frames;
+        // image2 and the *_pipe demuxers are what ffmpeg reads single pictures with
+        let format_name = &self.format.format_name;
+        let image_format = format_name == "image2" || format_name.ends_with("_pipe");
+        let animated_codec = matches!(video.codec_name.as_str(), "gif" | "apng");
+        if animated_codec && !has_audio {
+            return match frames {
+                Some(1) => Ok(MediaType::Image),
+                _ => Ok(MediaType::AnimatedImage),
+            };
+        }
+        if image_format || frames == Some(1) {
+            return Ok(MediaType::Image);
+        }
+        match (self.duration(), hint) {
+            (Some(_), Some(MediaType::Image)) if frames.is_none() && !has_audio => {
+                // heic and avif come in a mp4 style container, with a duration but one picture
+                Ok(MediaType::Image)
+            }
+            (Some(_), _) => Ok(MediaType::Video),
+            (None, Some(MediaType::Video)) => bail!("video stream without a duration"),
+            (None, _) => Ok(MediaType::Image),
+        }
+    }
+
+    /// Whether ffprobe left out something planning needs, like the `N/A` durations of some
+    /// webm recordings
+    pub fn has_gaps(&self) -> bool {
+        let video = self.video();
+        self.duration().is_none()
+            || video.is_some_and(|v| v.resolution().is_none() || v.fps().is_none())
+            || self.audio().is_some_and(|a| a.sample_rate.is_none())
+    }
+
+    /// Fills what's missing here with what `other`, a [`native::probe`] of the same file, found.
+    /// Streams only count as the same if their index and type agree.
+    pub fn fill_gaps(&mut self, other: Probe) {
+        for theirs in other.streams {
+            let Some(ours) = self
+                .streams
+                .iter_mut()
+                .find(|s| s.index == theirs.index && s.codec_type == theirs.codec_type)
+            else {
+                continue;
+            };
+            ours.duration = ours.duration.or(theirs.duration);
+            ours.width = ours.width.or(theirs.width);
+            ours.height = ours.height.or(theirs.height);
+            ours.nb_frames = ours.nb_frames.or(theirs.nb_frames);
+            ours.bit_rate = ours.bit_rate.or(theirs.bit_rate);
+            ours.sample_rate = ours.sample_rate.or(theirs.sample_rate);
+            ours.channels = ours.channels.or(theirs.channels);
+            if ours.fps().is_none() {
+                ours.r_frame_rate = theirs.r_frame_rate;
+                ours.avg_frame_rate = theirs.avg_frame_rate;
+            }
+        }
+        self.format.duration = self.format.duration.or(other.format.duration);
+        self.format.bit_rate = self.format.bit_rate.or(other.format.bit_rate);
+        self.format.size = self.format.size.or(other.format.size);
+    }
+}
+
+/// What the extension of `path` suggests it is. Only a hint, the streams ffprobe finds win.
+pub fn media_type_hint(path: &Path) -> Option<MediaType> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "webm" | "mp4" | "m4v" | "mov" | "avi" | "mpeg" | "mpg" | "mkv" | "ts" | "m2ts" | "mts"
+        | "3gp" | "flv" | "wmv" | "ogv" => Some(MediaType::Video),
+        "mp3" | "wav" | "ogg" | "oga" | "opus" | "flac" | "aiff" | "m4a" | "aac" | "wma"
+        | "alac" => Some(MediaType::Audio),
+        "jpg" | "jpeg" | "png" | "webp" | "exr" | "tiff" | "tif" | "bmp" | "raw" | "heic"
+        | "heif" | "avif" => Some(MediaType::Image),
+        "gif" | "apng" => Some(MediaType::AnimatedImage),
+        _ => None,
+    }
+}
+
+/// Frame rates come as a fraction, eg. 30000/1001. `0/0` means unknown.
+fn parse_rate(rate: &str) -> Option<f32> {
+    let (num, den) = rate.split_once('/')?;
+    let fps = num.parse::<f32>().ok()? / den.parse::<f32>().ok()?;
+    (fps.is_finite() && fps > 0.).then_some(fps)
+}
+
+/// Converts `00:00:00.000` (any number of `:` separated parts) to seconds
+pub(crate) fn parse_timestamp(timestamp: &str) -> Option<f32> {
+    timestamp
+        .split(':')
+        .rev()
+        .zip([1., 60., 3600., 24. * 3600.])
+        .try_fold(0., |secs, (part, unit)| {
+            Some(secs + part.trim().parse::<f32>().ok()? * unit)
+        })
+}
@@ -0,0 +1,1653 @@
+use anyhow::{bail, Context};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::{Format, Probe, SideData, Stream, StreamKind};
+
+/// How far into a file the first mp3 frame and ogg pages are looked for, and how much of its
+/// end is searched for the last ogg page or matroska cluster
+const SCAN_LEN: u64 = 64 * 1024;
+/// Header boxes and elements are read whole, anything bigger than this is corrupt
+const MAX_HEADER_LEN: u64 = 64 * 1024 * 1024;
+
+/// Reads streams, durations, dimensions and bitrates straight from the headers of
+/// Matroska/WebM, MP4/MOV, AVI, Ogg, FLAC, WAV, MP3, PNG/APNG, JPEG, GIF, WebP, BMP and TIFF files,
+/// in the shape ffprobe gives them. For when ffprobe is missing or leaves things out.
+pub fn probe(path: &Path) -> anyhow::Result<Probe> {
+    let mut file = File::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+    let size = file.metadata()?.len();
+    // tags in front of flac and mp3 files
+    let start = id3_len(&read_at(&mut file, 0, 10)?);
+    let head = read_at(&mut file, start, 16)?;
+    let riff = |kind: &[u8]| head.starts_with(b"RIFF") && head.get(8..12) == Some(kind);
+    let mut probe = if head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        matroska(&mut file, size)
+    } else if head.get(4..8) == Some(b"ftyp") {
+        mp4(&mut file, size)
+    } else if riff(b"AVI ") {
+        avi(&mut file)
+    } else if riff(b"WAVE") {
+        wav(&mut file, size)
+    } else if riff(b"WEBP") {
+        webp(&mut file, size)
+    } else if head.starts_with(b"OggS") {
+        ogg(&mut file, size)
+    } else if head.starts_with(b"fLaC") {
+        flac(&mut file, start)
+    } else if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png(&mut file, size)
+    } else if head.starts_with(&[0xFF, 0xD8]) {
+        jpeg(&mut file)
+    } else if head.starts_with(b"GIF8") {
+        gif(&mut file)
+    } else if head.starts_with(b"BM") {
+        bmp(&mut file)
+    } else if head.starts_with(b"II*\0") || head.starts_with(b"MM\0*") {
+        tiff(&mut file)
+    } else if start > 0 || mp3_frame(&head).is_some() {
+        mp3(&mut file, start, size)
+    } else {
+        bail!("nmb can't read this format without ffprobe")
+    }
+    .context("Can't read the headers")?;
+
+    probe.format.size = Some(size);
+    if probe.format.duration.is_none() {
+        probe.format.duration = probe
+            .streams
+            .iter()
+            .filter_map(|s| s.duration)
+            .reduce(f32::max);
+    }
+    if probe.format.bit_rate.is_none() {
+        probe.format.bit_rate = probe
+            .format
+            .duration
+            .filter(|d| *d > 0.)
+            .map(|d| (size as f64 * 8. / f64::from(d)) as u32);
+    }
+    Ok(probe)
+}
+
+fn new_probe(format_name: &str, streams: Vec<Stream>) -> Probe {
+    Probe {
+        streams,
+        format: Format {
+            format_name: format_name.to_owned(),
+            duration: None,
+            bit_rate: None,
+            size: None,
+            tags: HashMap::new(),
+        },
+    }
+}
+
+fn new_stream(index: usize, codec_type: StreamKind, codec_name: &str) -> Stream {
+    Stream {
+        index: index as u32,
+        codec_type,
+        codec_name: codec_name.to_owned(),
+        width: None,
+        height: None,
+        pix_fmt: None,
+        r_frame_rate: None,
+        avg_frame_rate: None,
+        duration: None,
+        bit_rate: None,
+        nb_frames: None,
+        sample_rate: None,
+        channels: None,
+        disposition: HashMap::new(),
+        tags: HashMap::new(),
+        side_data_list: vec![],
+    }
+}
+
+/// A single picture
+fn image(format_name: &str, codec_name: &str, width: u64, height: u64) -> Probe {
+    let mut stream = new_stream(0, StreamKind::Video, codec_name);
+    stream.width = u16::try_from(width).ok();
+    stream.height = u16::try_from(height).ok();
+    new_probe(format_name, vec![stream])
+}
+
+/// `num/den`, reduced, the way ffprobe prints rates
+fn rate(num: u64, den: u64) -> Option<String> {
+    if num == 0 || den == 0 {
+        return None;
+    }
+    let (mut a, mut b) = (num, den);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    Some(format!("{}/{}", num / a, den / a))
+}
+
+/// Like [`rate`], but frame durations are rounded to whole ticks, so 30fps at
+/// nanosecond ticks is 33333333ns. Snaps to the whole and NTSC rates those come from.
+fn frame_rate(ticks_per_sec: u64, ticks: u64) -> Option<String> {
+    let fps = ticks_per_sec as f64 / ticks as f64;
+    let tolerance = ticks_per_sec as f64 / (ticks as f64 - 0.5) - fps;
+    [1, 1001]
+        .into_iter()
+        .map(|den| ((fps * den as f64).round() as u64, den))
+        .find(|(num, den)| (*num as f64 / *den as f64 - fps).abs() <= tolerance)
+        .map_or_else(|| rate(ticks_per_sec, ticks), |(num, den)| rate(num, den))
+}
+
+fn secs(units: u64, per_sec: u64) -> Option<f32> {
+    (units > 0 && per_sec > 0).then(|| (units as f64 / per_sec as f64) as f32)
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![];
+    file.take(len).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Big endian unsigned number of `len` bytes at `at`
+fn be(data: &[u8], at: usize, len: usize) -> Option<u64> {
+    let bytes = data.get(at..at.checked_add(len)?)?;
+    Some(bytes.iter().fold(0, |n, b| n << 8 | u64::from(*b)))
+}
+
+fn le(data: &[u8], at: usize, len: usize) -> Option<u64> {
+    let bytes = data.get(at..at.checked_add(len)?)?;
+    Some(bytes.iter().rev().fold(0, |n, b| n << 8 | u64::from(*b)))
+}
+
+/// Size of the ID3v2 tag at the start of `head`, 0 if there's none
+fn id3_len(head: &[u8]) -> u64 {
+    if !head.starts_with(b"ID3") {
+        return 0;
+    }
+    // 7 bits per byte, so the size never looks like a frame sync
+    let size = head
+        .get(6..10)
+        .map_or(0, |s| s.iter().fold(0, |n, b| n << 7 | u64::from(b & 0x7F)));
+    let footer = head.get(5).is_some_and(|flags| flags & 0x10 != 0);
+    10 + size + if footer { 10 } else { 0 }
+}
+
+/// Audio formats of AVI and WAV, by their `wFormatTag`
+fn wave_codec(tag: u64, bits: u64) -> String {
+    match (tag, bits) {
+        (0x0001, 8) => "pcm_u8".into(),
+        (0x0001, bits) => format!("pcm_s{bits}le"),
+        (0x0003, bits) => format!("pcm_f{bits}le"),
+        (0x0006, _) => "pcm_alaw".into(),
+        (0x0007, _) => "pcm_mulaw".into(),
+        (0x0050, _) => "mp2".into(),
+        (0x0055, _) => "mp3".into(),
+        (0x00FF | 0x1610 | 0x706D, _) => "aac".into(),
+        (0x2000, _) => "ac3".into(),
+        (0xF1AC, _) => "flac".into(),
+        _ => "unknown".into(),
+    }
+}
+
+mod ebml {
+    pub const SEGMENT: u64 = 0x18538067;
+    pub const INFO: u64 = 0x1549A966;
+    pub const TIMESTAMP_SCALE: u64 = 0x2AD7B1;
+    pub const DURATION: u64 = 0x4489;
+    pub const TRACKS: u64 = 0x1654AE6B;
+    pub const TRACK_ENTRY: u64 = 0xAE;
+    pub const TRACK_UID: u64 = 0x73C5;
+    pub const TRACK_TYPE: u64 = 0x83;
+    pub const FLAG_DEFAULT: u64 = 0x88;
+    pub const CODEC_ID: u64 = 0x86;
+    pub const DEFAULT_DURATION: u64 = 0x23E383;
+    pub const VIDEO: u64 = 0xE0;
+    pub const PIXEL_WIDTH: u64 = 0xB0;
+    pub const PIXEL_HEIGHT: u64 = 0xBA;
+    pub const AUDIO: u64 = 0xE1;
+    pub const SAMPLING_FREQUENCY: u64 = 0xB5;
+    pub const CHANNELS: u64 = 0x9F;
+    pub const TAGS: u64 = 0x1254C367;
+    pub const TAG: u64 = 0x7373;
+    pub const TARGETS: u64 = 0x63C0;
+    pub const TAG_TRACK_UID: u64 = 0x63C5;
+    pub const SIMPLE_TAG: u64 = 0x67C8;
+    pub const TAG_NAME: u64 = 0x45A3;
+    pub const TAG_STRING: u64 = 0x4487;
+    pub const CLUSTER: u64 = 0x1F43B675;
+    pub const TIMESTAMP: u64 = 0xE7;
+    pub const SIMPLE_BLOCK: u64 = 0xA3;
+    pub const BLOCK_GROUP: u64 = 0xA0;
+    pub const BLOCK: u64 = 0xA1;
+}
+
+/// EBML variable length number at the start of `data` and its length. Ids keep their
+/// length marker bit, sizes don't.
+fn vint(data: &[u8], id: bool) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return None;
+    }
+    let first = match id {
+        true => u64::from(first),
+        false => u64::from(first) & (0xFF >> len),
+    };
+    let value = data
+        .get(1..len)?
+        .iter()
+        .fold(first, |n, b| n << 8 | u64::from(*b));
+    Some((value, len))
+}
+
+/// Children of an EBML element, as (id, body)
+fn elements(mut data: &[u8]) -> impl Iterator<Item = (u64, &[u8])> {
+    std::iter::from_fn(move || {
+        let (id, id_len) = vint(data, true)?;
+        let (size, size_len) = vint(data.get(id_len..)?, false)?;
+        let start = id_len + size_len;
+        // unknown sizes and cut off elements run to the end
+        let end = (start as u64).saturating_add(size).min(data.len() as u64) as usize;
+        let body = data.get(start..end)?;
+        data = &data[end..];
+        Some((id, body))
+    })
+}
+
+fn ebml_uint(data: &[u8]) -> u64 {
+    be(data, 0, data.len().min(8)).unwrap_or(0)
+}
+
+/// Strings may be padded with zeroes
+fn ebml_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .trim_end_matches('\0')
+        .to_owned()
+}
+
+fn ebml_float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f64::from(f32::from_be_bytes(data.try_into().ok()?))),
+        8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// Id, body offset and body size of the EBML element at `offset`, `None` if its size is
+/// unknown
+fn element_at(file: &mut File, offset: u64) -> anyhow::Result<(u64, u64, Option<u64>)> {
+    let head = read_at(file, offset, 12)?;
+    let (id, id_len) = vint(&head, true).context("Truncated element")?;
+    let (size, size_len) = head
+        .get(id_len..)
+        .and_then(|h| vint(h, false))
+        .context("Truncated element")?;
+    let unknown = size == (1 << (7 * size_len)) - 1;
+    Ok((
+        id,
+        offset + (id_len + size_len) as u64,
+        (!unknown).then_some(size),
+    ))
+}
+
+fn matroska(file: &mut File, size: u64) -> anyhow::Result<Probe> {
+    let (_, header, header_len) = element_at(file, 0)?;
+    let (id, segment, segment_len) =
+        element_at(file, header + header_len.context("Bad EBML header")?)?;
+    if id != ebml::SEGMENT {
+        bail!("No matroska segment");
+    }
+    let segment_end = segment_len.map_or(size, |l| (segment + l).min(size));
+
+    // the interesting parts are usually before the first cluster, except for the tags
+    let (mut info, mut tracks, mut tags) = (None, None, None);
+    let mut offset = segment;
+    while offset < segment_end {
+        let (id, body, len) = element_at(file, offset)?;
+        let Some(len) = len else {
+            // live recordings have clusters of unknown size, nothing follows those
+            break;
+        };
+        let element = match id {
+            ebml::INFO => &mut info,
+            ebml::TRACKS => &mut tracks,
+            ebml::TAGS => &mut tags,
+            _ => {
+                offset = body + len;
+                continue;
+            }
+        };
+        *element = Some(read_at(file, body, len.min(MAX_HEADER_LEN))?);
+        offset = body + len;
+    }
+
+    let mut scale = 1_000_000;
+    let mut duration = None;
+    for (id, body) in elements(info.as_deref().unwrap_or_default()) {
+        match id {
+            ebml::TIMESTAMP_SCALE => scale = ebml_uint(body),
+            ebml::DURATION => duration = ebml_float(body),
+            _ => {}
+        }
+    }
+
+    let mut streams = vec![];
+    let mut uids = vec![];
+    let tracks = tracks.context("No matroska tracks")?;
+    for (_, entry) in elements(&tracks).filter(|(id, _)| *id == ebml::TRACK_ENTRY) {
+        let (uid, stream) = matroska_track(entry, streams.len());
+        uids.push(uid);
+        streams.push(stream);
+    }
+
+    let mut probe = new_probe("matroska,webm", streams);
+    for (_, tag) in elements(tags.as_deref().unwrap_or_default()).filter(|(id, _)| *id == ebml::TAG)
+    {
+        let mut target = None;
+        let mut values = HashMap::new();
+        for (id, body) in elements(tag) {
+            match id {
+                ebml::TARGETS => {
+                    target = elements(body)
+                        .find(|(id, _)| *id == ebml::TAG_TRACK_UID)
+                        .map(|(_, uid)| ebml_uint(uid))
+                }
+                ebml::SIMPLE_TAG => {
+                    let (mut name, mut value) = (None, None);
+                    for (id, body) in elements(body) {
+                        match id {
+                            ebml::TAG_NAME => name = Some(ebml_string(body)),
+                            ebml::TAG_STRING => value = Some(ebml_string(body)),
+                            _ => {}
+                        }
+                    }
+                    if let (Some(name), Some(value)) = (name, value) {
+                        values.insert(name, value);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let tags = match target.and_then(|t| uids.iter().position(|uid| *uid == t)) {
+            Some(index) => &mut probe.streams[index].tags,
+            None => &mut probe.format.tags,
+        };
+        tags.extend(values);
+    }
+
+    let duration = match duration {
+        Some(duration) => Some(duration * scale as f64 / 1e9),
+        None => last_block_time(file, size, scale)?,
+    };
+    probe.format.duration = duration.map(|d| d as f32).filter(|d| *d > 0.);
+    Ok(probe)
+}
+
+/// Uid and stream of a `TrackEntry`
+fn matroska_track(entry: &[u8], index: usize) -> (u64, Stream) {
+    let (mut uid, mut kind, mut codec, mut frame_duration) = (0, 0, String::new(), 0);
+    let mut default = true;
+    let (mut width, mut height, mut sample_rate, mut channels) = (None, None, None, None);
+    for (id, body) in elements(entry) {
+        match id {
+            ebml::TRACK_UID => uid = ebml_uint(body),
+            ebml::TRACK_TYPE => kind = ebml_uint(body),
+            ebml::FLAG_DEFAULT => default = ebml_uint(body) != 0,
+            ebml::CODEC_ID => codec = ebml_string(body),
+            ebml::DEFAULT_DURATION => frame_duration = ebml_uint(body),
+            ebml::VIDEO => {
+                for (id, body) in elements(body) {
+                    match id {
+                        ebml::PIXEL_WIDTH => width = u16::try_from(ebml_uint(body)).ok(),
+                        ebml::PIXEL_HEIGHT => height = u16::try_from(ebml_uint(body)).ok(),
+                        _ => {}
+                    }
+                }
+            }
+            ebml::AUDIO => {
+                for (id, body) in elements(body) {
+                    match id {
+                        ebml::SAMPLING_FREQUENCY => {
+                            sample_rate = ebml_float(body).map(|r| r as u32)
+                        }
+                        ebml::CHANNELS => channels = u16::try_from(ebml_uint(body)).ok(),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let codec_type = match kind {
+        1 => StreamKind::Video,
+        2 => StreamKind::Audio,
+        0x11 => StreamKind::Subtitle,
+        _ => StreamKind::Other,
+    };
+    let codec_name = match codec.as_str() {
+        "V_MPEG4/ISO/AVC" => "h264".to_owned(),
+        "V_MPEGH/ISO/HEVC" => "hevc".to_owned(),
+        "V_MPEG4/ISO/SP" | "V_MPEG4/ISO/ASP" | "V_MPEG4/ISO/AP" => "mpeg4".to_owned(),
+        "V_MJPEG" => "mjpeg".to_owned(),
+        "A_MPEG/L3" => "mp3".to_owned(),
+        "A_MPEG/L2" => "mp2".to_owned(),
+        "S_TEXT/UTF8" => "subrip".to_owned(),
+        "S_TEXT/ASS" | "S_TEXT/SSA" => "ass".to_owned(),
+        "S_HDMV/PGS" => "hdmv_pgs_subtitle".to_owned(),
+        "S_VOBSUB" => "dvd_subtitle".to_owned(),
+        codec if codec.starts_with("A_AAC") => "aac".to_owned(),
+        // V_VP9, A_OPUS, A_FLAC, S_TEXT/WEBVTT and the like
+        codec => codec
+            .split_once('_')
+            .map_or(codec, |(_, name)| name.rsplit('/').next().unwrap_or(name))
+            .to_lowercase(),
+    };
+    let mut stream = new_stream(index, codec_type, &codec_name);
+    stream.width = width;
+    stream.height = height;
+    stream.sample_rate = sample_rate;
+    stream.channels = channels;
+    if codec_type == StreamKind::Video {
+        stream.r_frame_rate = frame_rate(1_000_000_000, frame_duration);
+        stream.avg_frame_rate = stream.r_frame_rate.clone();
+    }
+    stream
+        .disposition
+        .insert("default".into(), u8::from(default));
+    (uid, stream)
+}
+
+/// Live recordings, like the ones browsers make, don't have a duration. The timestamp of
+/// their last block is the closest thing.
+fn last_block_time(file: &mut File, size: u64, scale: u64) -> anyhow::Result<Option<f64>> {
+    let Ok(tail) = read_at(file, size.saturating_sub(SCAN_LEN), SCAN_LEN) else {
+        return Ok(None);
+    };
+    let Some((cluster_time, last)) = last_block(&tail) else {
+        return Ok(None);
+    };
+    let time = i64::try_from(cluster_time)
+        .ok()
+        .and_then(|time| time.checked_add(last))
+        .context("Corrupt cluster timestamp")?;
+    Ok(Some(time as f64 * scale as f64 / 1e9))
+}
+
+/// Timestamp of the last cluster in `tail`, and the latest block time relative to it
+fn last_block(tail: &[u8]) -> Option<(u64, i64)> {
+    let cluster = tail
+        .windows(4)
+        .rposition(|w| be(w, 0, 4) == Some(ebml::CLUSTER))?;
+    let (_, size_len) = vint(tail.get(cluster + 4..)?, false)?;
+    let mut cluster_time = None;
+    let mut last = 0;
+    for (id, body) in elements(tail.get(cluster + 4 + size_len..)?) {
+        let block = match id {
+            ebml::TIMESTAMP => {
+                cluster_time = Some(ebml_uint(body));
+                continue;
+            }
+            ebml::SIMPLE_BLOCK => body,
+            ebml::BLOCK_GROUP => match elements(body).find(|(id, _)| *id == ebml::BLOCK) {
+                Some((_, block)) => block,
+                None => continue,
+            },
+            _ => continue,
+        };
+        // track number, then the time relative to the cluster
+        let (_, track_len) = vint(block, false)?;
+        let relative = i16::from_be_bytes(block.get(track_len..track_len + 2)?.try_into().ok()?);
+        last = last.max(i64::from(relative));
+    }
+    Some((cluster_time?, last))
+}
+
+/// Boxes of an mp4 box, as (type, body)
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let (start, len) = match be(data, 0, 4)? {
+            1 => (16, be(data, 8, 8)?),
+            0 => (8, data.len() as u64),
+            len => (8, len),
+        };
+        let kind = data.get(4..8)?;
+        let end = len.min(data.len() as u64) as usize;
+        let body = data.get(start..end)?;
+        data = &data[end..];
+        Some((kind, body))
+    })
+}
+
+fn mp4(file: &mut File, size: u64) -> anyhow::Result<Probe> {
+    let (mut brand, mut moov, mut meta) = (vec![], None, None);
+    let mut offset = 0;
+    while offset + 8 <= size {
+        let head = read_at(file, offset, 16)?;
+        let (header, len) = match be(&head, 0, 4).context("Truncated box")? {
+            1 => (16, be(&head, 8, 8).context("Truncated box")?),
+            0 => (8, size - offset),
+            len => (8, len),
+        };
+        if len < header {
+            bail!("Corrupt box at {offset}");
+        }
+        let mut body = || read_at(file, offset + header, (len - header).min(MAX_HEADER_LEN));
+        match &head[4..8] {
+            b"ftyp" => brand = head.get(8..12).unwrap_or_default().to_vec(),
+            b"moov" => moov = Some(body()?),
+            b"meta" => meta = Some(body()?),
+            _ => {}
+        }
+        offset = offset
+            .checked_add(len)
+            .filter(|&next| next > offset)
+            .with_context(|| format!("Corrupt box at {offset}"))?;
+    }
+
+    let Some(moov) = moov else {
+        // heif and avif keep pictures as items, with their size in an `ispe` property
+        let codec = match brand.as_slice() {
+            b"avif" | b"avis" => "av1",
+            _ => "hevc",
+        };
+        let meta = meta.context("No moov box")?;
+        let (width, height) = heif_size(&meta).context("No picture size")?;
+        let mut probe = image("mov,mp4,m4a,3gp,3g2,mj2", codec, width, height);
+        probe.streams[0].nb_frames = Some(1);
+        return Ok(probe);
+    };
+
+    let mut probe = new_probe("mov,mp4,m4a,3gp,3g2,mj2", vec![]);
+    for (kind, body) in boxes(&moov) {
+        match kind {
+            b"mvhd" => {
+                let (timescale, duration) = timing(body);
+                probe.format.duration = secs(duration, timescale);
+            }
+            b"trak" => {
+                let index = probe.streams.len();
+                probe.streams.push(mp4_track(body, index));
+            }
+            _ => {}
+        }
+    }
+    Ok(probe)
+}
+
+fn mp4_track(trak: &[u8], index: usize) -> Stream {
+    let (mut handler, mut codec) = (&b"    "[..], &b"    "[..]);
+    let (mut timescale, mut duration) = (0, 0);
+    let (mut width, mut height, mut rotation) = (None, None, None);
+    let (mut channels, mut sample_rate) = (None, None);
+    let (mut samples, mut bytes) = (None, 0);
+
+    for (kind, body) in boxes(trak) {
+        match kind {
+            b"tkhd" => {
+                let base = match body.first() {
+                    Some(1) => 36,
+                    _ => 24,
+                };
+                // 16.16 fixed point display matrix and size
+                let matrix = base + 16;
+                let a = be(body, matrix, 4).map(|a| a as u32 as i32);
+                let b = be(body, matrix + 4, 4).map(|b| b as u32 as i32);
+                if let (Some(a), Some(b)) = (a, b) {
+                    let degrees = -f64::from(b).atan2(f64::from(a)).to_degrees();
+                    rotation = (degrees.round() != 0.).then_some(degrees as f32);
+                }
+                width = be(body, matrix + 36, 2);
+                height = be(body, matrix + 40, 2);
+            }
+            b"mdia" => {
+                for (kind, body) in boxes(body) {
+                    match kind {
+                        b"mdhd" => (timescale, duration) = timing(body),
+                        b"hdlr" => handler = body.get(8..12).unwrap_or(handler),
+                        b"minf" => {
+                            let tables = boxes(body).filter(|(kind, _)| *kind == b"stbl");
+                            for (kind, body) in tables.flat_map(|(_, stbl)| boxes(stbl)) {
+                                match kind {
+                                    b"stsd" => {
+                                        codec = body.get(12..16).unwrap_or(codec);
+                                        match handler {
+                                            b"vide" => {
+                                                width = be(body, 40, 2).or(width);
+                                                height = be(body, 42, 2).or(height);
+                                            }
+                                            _ => {
+                                                channels = be(body, 32, 2);
+                                                sample_rate = be(body, 40, 2);
+                                            }
+                                        }
+                                    }
+                                    b"stsz" => {
+                                        let size = be(body, 4, 4).unwrap_or(0);
+                                        let count = be(body, 8, 4).unwrap_or(0);
+                                        samples = Some(count);
+                                        bytes = match size {
+                                            0 => (0..count as usize)
+                                                .map_while(|i| be(body, 12 + 4 * i, 4))
+                                                .sum(),
+                                            size => size * count,
+                                        };
+                                    }
+                                    _ => {}
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let codec_type = match handler {
+        b"vide" => StreamKind::Video,
+        b"soun" => StreamKind::Audio,
+        b"sbtl" | b"subt" | b"text" => StreamKind::Subtitle,
+        _ => StreamKind::Other,
+    };
+    let codec_name = match codec {
+        b"avc1" | b"avc3" => "h264".to_owned(),
+        b"hvc1" | b"hev1" => "hevc".to_owned(),
+        b"av01" => "av1".to_owned(),
+        b"vp09" => "vp9".to_owned(),
+        b"vp08" => "vp8".to_owned(),
+        b"mp4v" => "mpeg4".to_owned(),
+        b"jpeg" | b"mjpa" => "mjpeg".to_owned(),
+        b"mp4a" => "aac".to_owned(),
+        b"Opus" => "opus".to_owned(),
+        b"fLaC" => "flac".to_owned(),
+        b"ac-3" => "ac3".to_owned(),
+        b"ec-3" => "eac3".to_owned(),
+        b".mp3" => "mp3".to_owned(),
+        b"alac" => "alac".to_owned(),
+        b"tx3g" => "mov_text".to_owned(),
+        codec => String::from_utf8_lossy(codec).trim().to_lowercase(),
+    };
+    let mut stream = new_stream(index, codec_type, &codec_name);
+    stream.duration = secs(duration, timescale);
+    stream.nb_frames = samples.filter(|_| codec_type == StreamKind::Video);
+    stream.bit_rate = stream
+        .duration
+        .filter(|_| bytes > 0)
+        .map(|d| (bytes as f64 * 8. / f64::from(d)) as u32);
+    match codec_type {
+        StreamKind::Video => {
+            stream.width = width.and_then(|w| u16::try_from(w).ok());
+            stream.height = height.and_then(|h| u16::try_from(h).ok());
+            stream.avg_frame_rate = rate(samples.unwrap_or(0) * timescale, duration);
+            stream.r_frame_rate = stream.avg_frame_rate.clone();
+            stream.side_data_list = rotation
+                .map(|r| vec![SideData { rotation: Some(r) }])
+                .unwrap_or_default();
+        }
+        StreamKind::Audio => {
+            stream.channels = channels.and_then(|c| u16::try_from(c).ok());
+            stream.sample_rate = match codec_name.as_str() {
+                // always decoded at 48kHz, whatever the header says
+                "opus" => Some(48_000),
+                _ => sample_rate.map(|r| r as u32).filter(|r| *r > 0),
+            };
+        }
+        _ => {}
+    }
+    stream
+}
+
+/// Timescale and duration of a `mvhd` or `mdhd` box, their layout only differs after those
+fn timing(body: &[u8]) -> (u64, u64) {
+    let (timescale, duration) = match body.first() {
+        Some(1) => (be(body, 20, 4), be(body, 24, 8)),
+        _ => (be(body, 12, 4), be(body, 16, 4)),
+    };
+    (timescale.unwrap_or(0), duration.unwrap_or(0))
+}
+
+/// Largest `ispe` of a heif `meta` box, the main picture rather than a thumbnail
+fn heif_size(meta: &[u8]) -> Option<(u64, u64)> {
+    // meta is a full box, version and flags come first
+    boxes(meta.get(4..)?)
+        .filter(|(kind, _)| *kind == b"iprp")
+        .flat_map(|(_, iprp)| boxes(iprp))
+        .filter(|(kind, _)| *kind == b"ipco")
+        .flat_map(|(_, ipco)| boxes(ipco))
+        .filter(|(kind, _)| *kind == b"ispe")
+        .filter_map(|(_, ispe)| Some((be(ispe, 4, 4)?, be(ispe, 8, 4)?)))
+        .max_by_key(|(w, h)| w * h)
+}
+
+/// RIFF chunks, as (id, body)
+fn chunks(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let kind = data.get(0..4)?;
+        let len = le(data, 4, 4)? as usize;
+        let end = (8 + len).min(data.len());
+        let body = data.get(8..end)?;
+        // chunks are padded to an even length
+        data = data.get(end + len % 2..).unwrap_or_default();
+        Some((kind, body))
+    })
+}
+
+fn avi(file: &mut File) -> anyhow::Result<Probe> {
+    let head = read_at(file, 12, 12)?;
+    if head.get(0..4) != Some(b"LIST") || head.get(8..12) != Some(b"hdrl") {
+        bail!("No AVI header list");
+    }
+    let len = le(&head, 4, 4).context("Truncated header")?;
+    let hdrl = read_at(file, 24, len.saturating_sub(4).min(MAX_HEADER_LEN))?;
+
+    let mut streams = vec![];
+    let lists = chunks(&hdrl).filter(|(kind, body)| *kind == b"LIST" && body.starts_with(b"strl"));
+    for (_, strl) in lists {
+        let (mut header, mut format) = (&[][..], &[][..]);
+        for (kind, body) in chunks(&strl[4..]) {
+            match kind {
+                b"strh" => header = body,
+                b"strf" => format = body,
+                _ => {}
+            }
+        }
+        let scale = le(header, 20, 4).unwrap_or(0);
+        let rate_ = le(header, 24, 4).unwrap_or(0);
+        let length = le(header, 32, 4).unwrap_or(0);
+        let index = streams.len();
+        let stream = match header.get(0..4) {
+            Some(b"vids") => {
+                let compression = format.get(16..20).unwrap_or_default();
+                let codec = match compression.to_ascii_uppercase().as_slice() {
+                    b"FMP4" | b"XVID" | b"DIVX" | b"DX50" | b"MP4V" => "mpeg4".to_owned(),
+                    b"H264" | b"X264" | b"AVC1" => "h264".to_owned(),
+                    b"HEVC" | b"H265" | b"HVC1" => "hevc".to_owned(),
+                    b"MJPG" => "mjpeg".to_owned(),
+                    b"VP80" => "vp8".to_owned(),
+                    b"VP90" => "vp9".to_owned(),
+                    _ => String::from_utf8_lossy(compression).trim().to_lowercase(),
+                };
+                let mut stream = new_stream(index, StreamKind::Video, &codec);
+                stream.width = le(format, 4, 4).and_then(|w| u16::try_from(w as u32 as i32).ok());
+                // negative heights are top-down bitmaps
+                stream.height = le(format, 8, 4)
+                    .and_then(|h| u16::try_from((h as u32 as i32).unsigned_abs()).ok());
+                stream.r_frame_rate = rate(rate_, scale);
+                stream.avg_frame_rate = stream.r_frame_rate.clone();
+                stream.nb_frames = (length > 0).then_some(length);
+                stream
+            }
+            Some(b"auds") => {
+                let tag = le(format, 0, 2).unwrap_or(0);
+                let bits = le(format, 14, 2).unwrap_or(0);
+                let mut stream = new_stream(index, StreamKind::Audio, &wave_codec(tag, bits));
+                stream.channels = le(format, 2, 2).map(|c| c as u16);
+                stream.sample_rate = le(format, 4, 4).map(|r| r as u32);
+                // VBR muxers leave a placeholder like 40 bytes/s in there
+                stream.bit_rate = le(format, 8, 4)
+                    .map(|b| (b * 8) as u32)
+                    .filter(|b| *b >= 8000);
+                stream
+            }
+            Some(b"txts") => new_stream(index, StreamKind::Subtitle, "unknown"),
+            _ => new_stream(index, StreamKind::Other, "unknown"),
+        };
+        // `rate / scale` units a second, `length` units long
+        let duration = secs(length * scale, rate_);
+        streams.push(Stream { duration, ..stream });
+    }
+    Ok(new_probe("avi", streams))
+}
+
+fn wav(file: &mut File, size: u64) -> anyhow::Result<Probe> {
+    let (mut format, mut data) = (None, None);
+    let mut offset = 12;
+    while offset + 8 <= size && (format.is_none() || data.is_none()) {
+        let head = read_at(file, offset, 8)?;
+        let len = le(&head, 4, 4).context("Truncated chunk")?;
+        match &head[0..4] {
+            b"fmt " => format = Some(read_at(file, offset + 8, len.min(64))?),
+            // streamed wavs leave the size at 0 or the maximum
+            b"data" => {
+                data = Some(match len {
+                    0 | 0xFFFF_FFFF => size - offset - 8,
+                    len => len.min(size - offset - 8),
+                })
+            }
+            _ => {}
+        }
+        offset += 8 + len + len % 2;
+    }
+
+    let format = format.context("No fmt chunk")?;
+    let mut tag = le(&format, 0, 2).unwrap_or(0);
+    // WAVE_FORMAT_EXTENSIBLE, the actual format starts its sub format guid
+    if tag == 0xFFFE {
+        tag = le(&format, 24, 2).unwrap_or(0);
+    }
+    let byte_rate = le(&format, 8, 4).unwrap_or(0);
+    let mut stream = new_stream(
+        0,
+        StreamKind::Audio,
+        &wave_codec(tag, le(&format, 14, 2).unwrap_or(0)),
+    );
+    stream.channels = le(&format, 2, 2).map(|c| c as u16);
+    stream.sample_rate = le(&format, 4, 4).map(|r| r as u32);
+    stream.bit_rate = Some((byte_rate * 8) as u32).filter(|b| *b > 0);
+    stream.duration = secs(data.unwrap_or(0), byte_rate);
+    Ok(new_probe("wav", vec![stream]))
+}
+
+/// An Ogg page: (header type, granule position, serial number, body, length)
+fn ogg_page(data: &[u8]) -> Option<(u8, u64, u64, &[u8], usize)> {
+    if !data.starts_with(b"OggS") {
+        return None;
+    }
+    let segments = *data.get(26)? as usize;
+    let body_len = data
+        .get(27..27 + segments)?
+        .iter()
+        .map(|l| *l as usize)
+        .sum::<usize>();
+    let body = data.get(27 + segments..27 + segments + body_len)?;
+    Some((
+        data[5],
+        le(data, 6, 8)?,
+        le(data, 14, 4)?,
+        body,
+        27 + segments + body_len,
+    ))
+}
+
+/// How an Ogg stream's granule positions turn into a duration
+enum Granules {
+    /// Samples at a rate, after skipping the first few
+    Samples { rate: u64, skip: u64 },
+    /// Theora's keyframe number and frames since, at a frame rate
+    Frames { shift: u32, num: u64, den: u64 },
+}
+
+fn ogg(file: &mut File, size: u64) -> anyhow::Result<Probe> {
+    let head = read_at(file, 0, SCAN_LEN)?;
+    // every stream starts with a page of its own, before any other page
+    let mut streams = vec![];
+    let mut at = 0;
+    while let Some((kind, _, serial, body, len)) = ogg_page(&head[at..]) {
+        if kind & 0x02 == 0 {
+            break;
+        }
+        if let Some((stream, granules)) = ogg_stream(body, streams.len()) {
+            streams.push((serial, stream, granules));
+        }
+        at += len;
+    }
+    if streams.is_empty() {
+        bail!("No Ogg streams nmb knows");
+    }
+
+    let tail_start = size.saturating_sub(SCAN_LEN);
+    let tail = read_at(file, tail_start, SCAN_LEN)?;
+    let mut last = HashMap::new();
+    for at in (0..tail.len()).filter(|at| tail[*at..].starts_with(b"OggS")) {
+        if let Some((_, granule, serial, _, _)) = ogg_page(&tail[at..]) {
+            // -1 means no packet ends on this page
+            if granule != u64::MAX {
+                last.insert(serial, granule);
+            }
+        }
+    }
+
+    let streams = streams
+        .into_iter()
+        .map(|(serial, stream, granules)| {
+            let granule = last.get(&serial).copied().unwrap_or(0);
+            let duration = match granules {
+                Granules::Samples { rate, skip } => secs(granule.saturating_sub(skip), rate),
+                Granules::Frames { shift, num, den } => {
+                    let frames = (granule >> shift) + (granule & ((1 << shift) - 1));
+                    let time = frames
+                        .checked_mul(den)
+                        .context("Corrupt granule position")?;
+                    secs(time, num)
+                }
+            };
+            Ok(Stream { duration, ..stream })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(new_probe("ogg", streams))
+}
+
+/// The stream an Ogg identification header starts
+fn ogg_stream(header: &[u8], index: usize) -> Option<(Stream, Granules)> {
+    if header.starts_with(b"\x01vorbis") {
+        let mut stream = new_stream(index, StreamKind::Audio, "vorbis");
+        let rate = le(header, 12, 4)?;
+        stream.channels = Some(u16::from(*header.get(11)?));
+        stream.sample_rate = Some(rate as u32);
+        stream.bit_rate = le(header, 20, 4).map(|b| b as u32).filter(|b| *b > 0);
+        Some((stream, Granules::Samples { rate, skip: 0 }))
+    } else if header.starts_with(b"OpusHead") {
+        let mut stream = new_stream(index, StreamKind::Audio, "opus");
+        stream.channels = Some(u16::from(*header.get(9)?));
+        // granules always count 48kHz samples
+        stream.sample_rate = Some(48_000);
+        let skip = le(header, 10, 2)?;
+        Some((stream, Granules::Samples { rate: 48_000, skip }))
+    } else if header.starts_with(b"\x7fFLAC") {
+        // mapping header, `fLaC`, then a regular flac metadata block
+        let (rate, channels, _) = streaminfo(header.get(17..)?)?;
+        let mut stream = new_stream(index, StreamKind::Audio, "flac");
+        stream.sample_rate = Some(rate as u32);
+        stream.channels = Some(channels);
+        Some((stream, Granules::Samples { rate, skip: 0 }))
+    } else if header.starts_with(b"\x80theora") {
+        let mut stream = new_stream(index, StreamKind::Video, "theora");
+        stream.width = u16::try_from(be(header, 14, 3)?).ok();
+        stream.height = u16::try_from(be(header, 17, 3)?).ok();
+        let (num, den) = (be(header, 22, 4)?, be(header, 26, 4)?);
+        stream.r_frame_rate = rate(num, den);
+        stream.avg_frame_rate = stream.r_frame_rate.clone();
+        let shift = u32::from((header.get(40)? & 0x03) << 3 | header.get(41)? >> 5);
+        Some((stream, Granules::Frames { shift, num, den }))
+    } else {
+        None
+    }
+}
+
+/// Sample rate, channels and total samples of a flac `STREAMINFO` block
+fn streaminfo(block: &[u8]) -> Option<(u64, u16, u64)> {
+    // 20 bits rate, 3 bits channels - 1, 5 bits bits per sample - 1, 36 bits samples
+    let packed = be(block, 10, 8)?;
+    let rate = packed >> 44;
+    let channels = ((packed >> 41) & 0x07) as u16 + 1;
+    let samples = packed & 0xF_FFFF_FFFF;
+    (rate > 0).then_some((rate, channels, samples))
+}
+
+fn flac(file: &mut File, start: u64) -> anyhow::Result<Probe> {
+    // `fLaC`, the block header, then STREAMINFO which always comes first
+    let head = read_at(file, start, 4 + 4 + 34)?;
+    let (rate, channels, samples) = head
+        .get(8..)
+        .and_then(streaminfo)
+        .context("Bad STREAMINFO")?;
+    let mut stream = new_stream(0, StreamKind::Audio, "flac");
+    stream.sample_rate = Some(rate as u32);
+    stream.channels = Some(channels);
+    stream.duration = secs(samples, rate);
+    Ok(new_probe("flac", vec![stream]))
+}
+
+/// An MPEG audio layer 3 frame header
+struct Mp3Frame {
+    bitrate: u64, //kbits
+    sample_rate: u64,
+    samples: u64,
+    mono: bool,
+    mpeg1: bool,
+    len: usize,
+}
+
+fn mp3_frame(data: &[u8]) -> Option<Mp3Frame> {
+    const MPEG1: [u64; 15] = [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ];
+    const MPEG2: [u64; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    let header = be(data, 0, 4)?;
+    // sync, then version 2.5/reserved/2/1 and layer reserved/3/2/1
+    let version = (header >> 19) & 0x03;
+    if header >> 21 != 0x7FF || version == 1 || (header >> 17) & 0x03 != 1 {
+        return None;
+    }
+    let mpeg1 = version == 3;
+    let bitrate = *[MPEG1, MPEG2][usize::from(!mpeg1)].get(((header >> 12) & 0x0F) as usize)?;
+    let sample_rate = *[44_100, 48_000, 32_000].get(((header >> 10) & 0x03) as usize)?
+        >> [2, 0, 1, 0][version as usize];
+    if bitrate == 0 {
+        return None;
+    }
+    let samples = if mpeg1 { 1152 } else { 576 };
+    let padding = (header >> 9) & 0x01;
+    Some(Mp3Frame {
+        bitrate,
+        sample_rate,
+        samples,
+        mono: (header >> 6) & 0x03 == 3,
+        mpeg1,
+        len: (samples / 8 * bitrate * 1000 / sample_rate + padding) as usize,
+    })
+}
+
+fn mp3(file: &mut File, start: u64, size: u64) -> anyhow::Result<Probe> {
+    let data = read_at(file, start, SCAN_LEN)?;
+    // a frame followed by another one, so stray sync bits in leftover tags don't count
+    let (at, frame) = (0..data.len())
+        .filter_map(|at| Some((at, mp3_frame(&data[at..])?)))
+        .find(|(at, frame)| {
+            let next = at + frame.len;
+            next >= data.len() || mp3_frame(&data[next..]).is_some()
+        })
+        .context("No mp3 frames")?;
+
+    let tag = read_at(file, size.saturating_sub(128), 3)?;
+    let audio_end = size.saturating_sub(if tag == b"TAG" { 128 } else { 0 });
+    let audio_bytes = audio_end.saturating_sub(start + at as u64);
+
+    // VBR files count their frames in a Xing/Info or VBRI header in the first frame
+    let side_info = match (frame.mpeg1, frame.mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    let xing = at + 4 + side_info;
+    let vbri = at + 4 + 32;
+    let frames = match (data.get(xing..xing + 4), data.get(vbri..vbri + 4)) {
+        (Some(b"Xing" | b"Info"), _) if be(&data, xing + 4, 4).unwrap_or(0) & 0x01 != 0 => {
+            be(&data, xing + 8, 4)
+        }
+        (_, Some(b"VBRI")) => be(&data, vbri + 14, 4),
+        _ => None,
+    };
+
+    let mut stream = new_stream(0, StreamKind::Audio, "mp3");
+    stream.sample_rate = Some(frame.sample_rate as u32);
+    stream.channels = Some(if frame.mono { 1 } else { 2 });
+    match frames {
+        Some(frames) => {
+            stream.duration = secs(frames * frame.samples, frame.sample_rate);
+            stream.bit_rate = stream
+                .duration
+                .map(|d| (audio_bytes as f64 * 8. / f64::from(d)) as u32);
+        }
+        None => {
+            stream.duration = secs(audio_bytes * 8, frame.bitrate * 1000);
+            stream.bit_rate = Some((frame.bitrate * 1000) as u32);
+        }
+    }
+    Ok(new_probe("mp3", vec![stream]))
+}
+
+fn png(file: &mut File, size: u64) -> anyhow::Result<Probe> {
+    let (mut width, mut height) = (None, None);
+    let mut frames = None;
+    let mut duration = 0.;
+    let mut offset = 8;
+    while offset + 12 <= size {
+        let head = read_at(file, offset, 8)?;
+        let len = be(&head, 0, 4).context("Truncated chunk")?;
+        match &head[4..8] {
+            b"IHDR" | b"acTL" | b"fcTL" => {
+                let body = read_at(file, offset + 8, len.min(32))?;
+                match &head[4..8] {
+                    b"IHDR" => (width, height) = (be(&body, 0, 4), be(&body, 4, 4)),
+                    b"acTL" => frames = be(&body, 0, 4),
+                    _ => {
+                        let num = be(&body, 20, 2).unwrap_or(0);
+                        // a denominator of 0 means hundredths of a second
+                        let den = be(&body, 22, 2).filter(|d| *d > 0).unwrap_or(100);
+                        duration += num as f64 / den as f64;
+                    }
+                }
+            }
+            // only animated pngs need looking past the first picture, for the frame delays
+            b"IDAT" if frames.is_none() => break,
+            b"IEND" => break,
+            _ => {}
+        }
+        offset += 12 + len;
+    }
+    let (width, height) = (width.context("No IHDR")?, height.context("No IHDR")?);
+    Ok(match frames {
+        Some(frames) => {
+            let mut probe = image("apng", "apng", width, height);
+            probe.streams[0].nb_frames = Some(frames);
+            probe.streams[0].duration = Some(duration as f32).filter(|d| *d > 0.);
+            probe
+        }
+        None => image("png_pipe", "png", width, height),
+    })
+}
+
+fn jpeg(file: &mut File) -> anyhow::Result<Probe> {
+    let mut offset = 2;
+    loop {
+        let head = read_at(file, offset, 9)?;
+        if head.len() < 4 || head[0] != 0xFF {
+            bail!("No frame header");
+        }
+        match head[1] {
+            // fill bytes before a marker
+            0xFF => offset += 1,
+            // markers without a length
+            0x01 | 0xD0..=0xD9 => offset += 2,
+            // start of frame, except for DHT, JPG and DAC which share the range
+            0xC0..=0xCF if !matches!(head[1], 0xC4 | 0xC8 | 0xCC) => {
+                let height = be(&head, 5, 2).context("Truncated frame header")?;
+                let width = be(&head, 7, 2).context("Truncated frame header")?;
+                return Ok(image("image2", "mjpeg", width, height));
+            }
+            _ => offset += 2 + be(&head, 2, 2).unwrap_or(0),
+        }
+    }
+}
+
+fn gif(file: &mut File) -> anyhow::Result<Probe> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file);
+    let mut header = [0; 13];
+    reader.read_exact(&mut header)?;
+    let skip_color_table = |reader: &mut BufReader<_>, flags: u8| match flags & 0x80 {
+        0 => Ok(()),
+        _ => reader.seek_relative(3 << ((flags & 0x07) + 1)),
+    };
+    skip_color_table(&mut reader, header[10])?;
+
+    let mut frames = 0;
+    let mut duration = 0;
+    let mut delay = None;
+    // a cut off gif still has the frames before the cut
+    while let Ok(block) = byte(&mut reader) {
+        let read = match block {
+            0x2C => {
+                let mut descriptor = [0; 9];
+                frames += 1;
+                // like ffmpeg, too short delays become the default of 1/10s
+                duration += delay.take().filter(|d| *d >= 2).unwrap_or(10);
+                reader
+                    .read_exact(&mut descriptor)
+                    .and_then(|_| skip_color_table(&mut reader, descriptor[8]))
+                    .and_then(|_| byte(&mut reader))
+                    .and_then(|_| skip_sub_blocks(&mut reader))
+            }
+            0x21 => match byte(&mut reader) {
+                // graphic control extension, with the delay of the next picture
+                Ok(0xF9) => {
+                    let mut control = [0; 5];
+                    reader.read_exact(&mut control).and_then(|_| {
+                        delay = le(&control, 2, 2);
+                        skip_sub_blocks(&mut reader)
+                    })
+                }
+                Ok(_) => skip_sub_blocks(&mut reader),
+                Err(e) => Err(e),
+            },
+            _ => break,
+        };
+        if read.is_err() {
+            break;
+        }
+    }
+
+    let mut probe = image(
+        "gif",
+        "gif",
+        le(&header, 6, 2).unwrap_or(0),
+        le(&header, 8, 2).unwrap_or(0),
+    );
+    probe.streams[0].nb_frames = Some(frames).filter(|f| *f > 0);
+    probe.streams[0].duration = secs(duration, 100).filter(|_| frames > 1);
+    Ok(probe)
+}
+
+fn byte(reader: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Skips gif data sub-blocks, up to and including the empty one ending them
+fn skip_sub_blocks(reader: &mut BufReader<&mut File>) -> io::Result<()> {
+    loop {
+        match byte(reader)? {
+            0 => return Ok(()),
+            len => reader.seek_relative(i64::from(len))?,
+        }
+    }
+}
+
+fn webp(file: &mut File, size: u64) -> anyhow::Result<Probe> {
+    let (mut width, mut height) = (None, None);
+    let (mut animated, mut frames, mut duration) = (false, 0, 0);
+    let mut offset = 12;
+    while offset + 8 <= size {
+        let head = read_at(file, offset, 8)?;
+        let len = le(&head, 4, 4).context("Truncated chunk")?;
+        let body = read_at(file, offset + 8, len.min(16))?;
+        match &head[0..4] {
+            b"VP8X" => {
+                animated = body.first().is_some_and(|flags| flags & 0x02 != 0);
+                width = le(&body, 4, 3).map(|w| w + 1);
+                height = le(&body, 7, 3).map(|h| h + 1);
+            }
+            // lossy, a keyframe start code then 14 bit sizes
+            b"VP8 " if body.get(3..6) == Some(&[0x9D, 0x01, 0x2A]) => {
+                width = width.or(le(&body, 6, 2).map(|w| w & 0x3FFF));
+                height = height.or(le(&body, 8, 2).map(|h| h & 0x3FFF));
+            }
+            // lossless, a signature byte then 14 bit sizes - 1
+            b"VP8L" if body.first() == Some(&0x2F) => {
+                let bits = le(&body, 1, 4).unwrap_or(0);
+                width = width.or(Some((bits & 0x3FFF) + 1));
+                height = height.or(Some((bits >> 14 & 0x3FFF) + 1));
+            }
+            b"ANMF" => {
+                frames += 1;
+                duration += le(&body, 12, 3).unwrap_or(0);
+            }
+            _ => {}
+        }
+        if !animated && width.is_some() {
+            break;
+        }
+        offset += 8 + len + len % 2;
+    }
+    let mut probe = image(
+        "webp_pipe",
+        "webp",
+        width.context("No picture size")?,
+        height.context("No picture size")?,
+    );
+    if animated {
+        probe.streams[0].nb_frames = Some(frames);
+        probe.streams[0].duration = secs(duration, 1000);
+    }
+    Ok(probe)
+}
+
+fn bmp(file: &mut File) -> anyhow::Result<Probe> {
+    let head = read_at(file, 0, 26)?;
+    let width = le(&head, 18, 4).context("Truncated header")?;
+    // negative heights are top-down bitmaps
+    let height = le(&head, 22, 4).context("Truncated header")?;
+    Ok(image(
+        "bmp_pipe",
+        "bmp",
+        u64::from((width as u32 as i32).unsigned_abs()),
+        u64::from((height as u32 as i32).unsigned_abs()),
+    ))
+}
+
+fn tiff(file: &mut File) -> anyhow::Result<Probe> {
+    let head = read_at(file, 0, 8)?;
+    let number = |data: &[u8], at, len| match head[0] {
+        b'I' => le(data, at, len),
+        _ => be(data, at, len),
+    };
+    // the first directory is the main picture, its entries are 12 bytes each
+    let directory = number(&head, 4, 4).context("Truncated header")?;
+    let count = number(&read_at(file, directory, 2)?, 0, 2).context("Truncated directory")?;
+    let entries = read_at(file, directory + 2, count * 12)?;
+    let (mut width, mut height) = (None, None);
+    for entry in entries.chunks_exact(12) {
+        // SHORT or LONG, either way the value sits at the start of the last 4 bytes
+        let value = match number(entry, 2, 2) {
+            Some(3) => number(entry, 8, 2),
+            _ => number(entry, 8, 4),
+        };
+        match number(entry, 0, 2) {
+            Some(256) => width = value,
+            Some(257) => height = value,
+            _ => {}
+        }
+    }
+    Ok(image(
+        "tiff_pipe",
+        "tiff",
+        width.context("No picture size")?,
+        height.context("No picture size")?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::MediaType;
+    use std::io::Write;
+
+    fn probe_bytes(data: &[u8]) -> Probe {
+        try_probe_bytes(data).unwrap()
+    }
+
+    fn try_probe_bytes(data: &[u8]) -> anyhow::Result<Probe> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(data).unwrap();
+        probe(file.path())
+    }
+
+    fn riff_chunk(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        [kind, &(body.len() as u32).to_le_bytes(), body].concat()
+    }
+
+    fn mp4_box(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u32 + 8).to_be_bytes(), kind, body].concat()
+    }
+
+    fn png_chunk(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        // nothing checks the crc
+        [&(body.len() as u32).to_be_bytes(), kind, body, &[0; 4]].concat()
+    }
+
+    fn ogg_page(kind: u8, granule: u64, body: &[u8]) -> Vec<u8> {
+        let header = [
+            b"OggS",
+            &[0, kind][..],
+            &granule.to_le_bytes(),
+            &[7, 0, 0, 0],
+            &[0; 8],
+        ];
+        [&header.concat(), &[1, body.len() as u8][..], body].concat()
+    }
+
+    #[test]
+    fn wav_lasts_as_long_as_its_data() {
+        let format = [
+            &1u16.to_le_bytes()[..],
+            &1u16.to_le_bytes(),
+            &8000u32.to_le_bytes(),
+            &8000u32.to_le_bytes(),
+            &1u16.to_le_bytes(),
+            &8u16.to_le_bytes(),
+        ]
+        .concat();
+        let body = [
+            &b"WAVE"[..],
+            &riff_chunk(b"fmt ", &format),
+            &riff_chunk(b"data", &[0x80; 12000]),
+        ]
+        .concat();
+        let probe = probe_bytes(&riff_chunk(b"RIFF", &body));
+
+        let audio = probe.audio().unwrap();
+        assert_eq!(audio.codec_name, "pcm_u8");
+        assert_eq!((audio.sample_rate, audio.channels), (Some(8000), Some(1)));
+        assert_eq!(audio.bit_rate, Some(64_000));
+        assert_eq!(probe.duration(), Some(1.5));
+    }
+
+    #[test]
+    fn flac_counts_samples() {
+        let packed: u64 = 44_100 << 44 | 1 << 41 | 15 << 36 | 88_200;
+        let streaminfo = [&[0; 10][..], &packed.to_be_bytes(), &[0; 16]].concat();
+        let data = [&b"fLaC"[..], &[0x80, 0, 0, 34], &streaminfo].concat();
+        let probe = probe_bytes(&data);
+
+        let audio = probe.audio().unwrap();
+        assert_eq!(audio.codec_name, "flac");
+        assert_eq!((audio.sample_rate, audio.channels), (Some(44_100), Some(2)));
+        assert_eq!(probe.duration(), Some(2.));
+    }
+
+    #[test]
+    fn cbr_mp3_behind_id3_tags() {
+        // MPEG 1 layer 3, 128kbit/s, 44.1kHz, stereo: 417 byte frames
+        let frame = [&[0xFF, 0xFB, 0x90, 0x00][..], &[0; 413]].concat();
+        let id3 = [&b"ID3"[..], &[4, 0, 0, 0, 0, 0, 20], &[0; 20]].concat();
+        let data = [id3, frame.repeat(10)].concat();
+        let probe = probe_bytes(&data);
+
+        let audio = probe.audio().unwrap();
+        assert_eq!(audio.codec_name, "mp3");
+        assert_eq!((audio.sample_rate, audio.channels), (Some(44_100), Some(2)));
+        assert_eq!(probe.audio_kbit_rate(), Some(128));
+        assert_eq!(probe.duration(), Some(4170. * 8. / 128_000.));
+    }
+
+    #[test]
+    fn apng_is_animated_and_png_is_not() {
+        let ihdr = png_chunk(
+            b"IHDR",
+            &[
+                &640u32.to_be_bytes()[..],
+                &480u32.to_be_bytes(),
+                &[8, 6, 0, 0, 0],
+            ]
+            .concat(),
+        );
+        // 1/4s frames, numerator and denominator at the end of the frame control
+        let fctl = png_chunk(
+            b"fcTL",
+            &[
+                &[0; 20][..],
+                &1u16.to_be_bytes(),
+                &4u16.to_be_bytes(),
+                &[0, 0],
+            ]
+            .concat(),
+        );
+        let signature = b"\x89PNG\r\n\x1a\n";
+        let png = [
+            &signature[..],
+            &ihdr,
+            &png_chunk(b"IDAT", &[0; 8]),
+            &png_chunk(b"IEND", &[]),
+        ]
+        .concat();
+        let apng = [
+            &signature[..],
+            &ihdr,
+            &png_chunk(b"acTL", &[&3u32.to_be_bytes()[..], &[0; 4]].concat()),
+            &fctl,
+            &png_chunk(b"IDAT", &[0; 8]),
+            &fctl,
+            &png_chunk(b"fdAT", &[0; 8]),
+            &fctl,
+            &png_chunk(b"fdAT", &[0; 8]),
+            &png_chunk(b"IEND", &[]),
+        ]
+        .concat();
+
+        let png = probe_bytes(&png);
+        assert_eq!(png.media_type(None).unwrap(), MediaType::Image);
+        assert_eq!(png.video().unwrap().resolution(), Some((640, 480)));
+        let apng = probe_bytes(&apng);
+        assert_eq!(apng.media_type(None).unwrap(), MediaType::AnimatedImage);
+        assert_eq!(apng.video().unwrap().nb_frames, Some(3));
+        assert_eq!(apng.duration(), Some(0.75));
+    }
+
+    #[test]
+    fn gif_frames_add_up_their_delays() {
+        let frame = |delay: u16| {
+            [
+                &[0x21, 0xF9, 4, 0][..],
+                &delay.to_le_bytes(),
+                &[0, 0],
+                &[0x2C, 0, 0, 0, 0, 2, 0, 2, 0, 0],
+                &[2, 1, 0, 0],
+            ]
+            .concat()
+        };
+        // a 2 color global table, then a frame of the default 1/10s
+        let header = [
+            &b"GIF89a"[..],
+            &2u16.to_le_bytes(),
+            &2u16.to_le_bytes(),
+            &[0x80, 0, 0],
+            &[0; 6],
+        ]
+        .concat();
+        let data = [header, frame(5), frame(5), frame(0), vec![0x3B]].concat();
+        let probe = probe_bytes(&data);
+
+        let video = probe.video().unwrap();
+        assert_eq!(video.resolution(), Some((2, 2)));
+        assert_eq!(video.nb_frames, Some(3));
+        assert_eq!(probe.media_type(None).unwrap(), MediaType::AnimatedImage);
+        assert_eq!(probe.duration(), Some(0.2));
+    }
+
+    #[test]
+    fn rotated_mp4() {
+        let tkhd = {
+            let mut tkhd = vec![0; 84];
+            // 90° clockwise: a = 0, b = 1, c = -1 in 16.16
+            tkhd[44..48].copy_from_slice(&0x10000u32.to_be_bytes());
+            tkhd[52..56].copy_from_slice(&(-0x10000i32).to_be_bytes());
+            tkhd[76..80].copy_from_slice(&(1920u32 << 16).to_be_bytes());
+            tkhd[80..84].copy_from_slice(&(1080u32 << 16).to_be_bytes());
+            tkhd
+        };
+        let timing = |timescale: u32, duration: u32| {
+            [
+                &[0; 12][..],
+                &timescale.to_be_bytes(),
+                &duration.to_be_bytes(),
+                &[0; 80],
+            ]
+            .concat()
+        };
+        let entry = [
+            &[0; 24][..],
+            &1920u16.to_be_bytes(),
+            &1080u16.to_be_bytes(),
+            &[0; 50],
+        ]
+        .concat();
+        let stsd = [&[0; 4][..], &1u32.to_be_bytes(), &mp4_box(b"avc1", &entry)].concat();
+        let stsz = [&[0; 4][..], &1000u32.to_be_bytes(), &60u32.to_be_bytes()].concat();
+        let stbl = [mp4_box(b"stsd", &stsd), mp4_box(b"stsz", &stsz)].concat();
+        let mdia = [
+            mp4_box(b"mdhd", &timing(30, 60)),
+            mp4_box(b"hdlr", &[&[0; 8][..], b"vide", &[0; 13]].concat()),
+            mp4_box(b"minf", &mp4_box(b"stbl", &stbl)),
+        ]
+        .concat();
+        let trak = [mp4_box(b"tkhd", &tkhd), mp4_box(b"mdia", &mdia)].concat();
+        let moov = [
+            mp4_box(b"mvhd", &timing(1000, 2000)),
+            mp4_box(b"trak", &trak),
+        ]
+        .concat();
+        let data = [
+            mp4_box(b"ftyp", b"isom\0\0\x02\0isom"),
+            mp4_box(b"moov", &moov),
+        ]
+        .concat();
+        let probe = probe_bytes(&data);
+
+        let video = probe.video().unwrap();
+        assert_eq!(video.codec_name, "h264");
+        assert_eq!(video.rotation(), -90);
+        assert_eq!(video.resolution(), Some((1080, 1920)));
+        assert_eq!(video.fps(), Some(30.));
+        assert_eq!(video.bit_rate, Some(240_000));
+        assert_eq!(probe.duration(), Some(2.));
+        assert_eq!(probe.media_type(None).unwrap(), MediaType::Video);
+    }
+
+    #[test]
+    fn corrupt_mp4_boxes_fail_instead_of_panicking() {
+        // a largesize that overflows the offset, which used to wrap around and loop forever
+        let free = [
+            &1u32.to_be_bytes()[..],
+            b"free",
+            &0xFFFF_FFFF_FFFF_FFF0u64.to_be_bytes(),
+        ]
+        .concat();
+        let data = [mp4_box(b"ftyp", b"isom\0\0\x02\0"), free].concat();
+        let error = try_probe_bytes(&data).unwrap_err();
+        assert!(format!("{error:#}").contains("Corrupt box"), "{error:#}");
+
+        // too short for a brand
+        assert!(try_probe_bytes(b"\0\0\0\x0cftyp").is_err());
+    }
+
+    #[test]
+    fn ogg_opus_skips_its_pre_skip() {
+        let head = [
+            &b"OpusHead"[..],
+            &[1, 2],
+            &312u16.to_le_bytes(),
+            &48_000u32.to_le_bytes(),
+            &[0; 3],
+        ]
+        .concat();
+        let data = [
+            ogg_page(0x02, 0, &head),
+            ogg_page(0, u64::MAX, b"OpusTags"),
+            ogg_page(0x04, 96_312, &[0; 8]),
+        ]
+        .concat();
+        let probe = probe_bytes(&data);
+
+        let audio = probe.audio().unwrap();
+        assert_eq!(audio.codec_name, "opus");
+        assert_eq!((audio.sample_rate, audio.channels), (Some(48_000), Some(2)));
+        assert_eq!(probe.duration(), Some(2.));
+    }
+
+    #[test]
+    fn overflowing_theora_granules_are_an_error() {
+        // frames at 1/(2^32 - 1) fps, without a keyframe shift
+        let head = [
+            &b"\x80theora"[..],
+            &[0; 7],
+            &[0, 0, 16, 0, 0, 16],
+            &[0; 2],
+            &1u32.to_be_bytes(),
+            &u32::MAX.to_be_bytes(),
+            &[0; 12],
+        ]
+        .concat();
+        let data = [
+            ogg_page(0x02, 0, &head),
+            ogg_page(0x04, u64::MAX - 1, &[0; 8]),
+        ]
+        .concat();
+        let error = try_probe_bytes(&data).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("Corrupt granule"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn overflowing_matroska_cluster_timestamps_are_an_error() {
+        // no duration, so it comes from the last block: at i64::MAX, 1 past its cluster
+        let data = [
+            &[0x1A, 0x45, 0xDF, 0xA3, 0x80][..],
+            &[0x18, 0x53, 0x80, 0x67, 0xFF],
+            &[0x15, 0x49, 0xA9, 0x66, 0x80],
+            // a video track, nothing else about it
+            &[0x16, 0x54, 0xAE, 0x6B, 0x85, 0xAE, 0x83, 0x83, 0x81, 0x01],
+            &[0x1F, 0x43, 0xB6, 0x75, 0xFF],
+            &[0xE7, 0x88],
+            &i64::MAX.to_be_bytes(),
+            &[0xA3, 0x84, 0x81, 0, 1, 0],
+        ]
+        .concat();
+        let error = try_probe_bytes(&data).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("Corrupt cluster"),
+            "{error:#}"
+        );
+    }
+
+    #[test]
+    fn unknown_formats_are_an_error() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"just some text, not media").unwrap();
+        assert!(probe(file.path()).is_err());
+    }
+
+    #[test]
+    fn fills_what_ffprobe_left_out() {
+        let mut ffprobe: Probe = serde_json::from_value(serde_json::json!({
+            "streams": [{ "index": 0, "codec_type": "audio", "codec_name": "flac", "duration": "N/A" }],
+            "format": { "format_name": "flac", "duration": "N/A" },
+        }))
+        .unwrap();
+        assert!(ffprobe.has_gaps());
+
+        let packed: u64 = 48_000 << 44 | 1 << 41 | 15 << 36 | 144_000;
+        let streaminfo = [&[0; 10][..], &packed.to_be_bytes(), &[0; 16]].concat();
+        ffprobe.fill_gaps(probe_bytes(
+            &[&b"fLaC"[..], &[0x80, 0, 0, 34], &streaminfo].concat(),
+        ));
+        assert!(!ffprobe.has_gaps());
+        assert_eq!(ffprobe.duration(), Some(3.));
+        assert_eq!(ffprobe.audio().unwrap().sample_rate, Some(48_000));
+    }
+}
@@ -0,0 +1,339 @@
+use std::path::Path;
+
+use crate::{AudioCodec, VideoCodec};
+
+/// How far under/over the average bitrate encoders are allowed to go
+const MIN_RATE_FACTOR: f32 = 0.5;
+const MAX_RATE_FACTOR: f32 = 1.45;
+
+/// What a single pass of a size constrained two-pass encode needs to know
+pub struct PassSettings<'a> {
+    pub pass: u8,
+    pub passlogfile: &'a Path,
+    pub video_bitrate: f32, //kbits
+    pub threads: u16,
+}
+
+/// Encoder specific arguments of a video codec. Each codec owns its rate control, two-pass
+/// mechanics, threading and GOP settings, so adding a codec doesn't touch the others.
+pub trait CodecProfile: Sync {
+    fn video_encoder(&self) -> &'static str;
+    /// Audio codec it's paired with, unless [`crate::plan::Constraints::audio_codec`] replaces it
+    fn audio_codec(&self) -> AudioCodec;
+    /// Container extension of the final output
+    fn extension(&self) -> &'static str;
+    /// Everything video related for one pass, after `-vcodec` has been set
+    fn video_args(&self, settings: &PassSettings) -> Vec<String>;
+    /// Lowest and highest bitrate the encoder gets told about, around `video_bitrate`
+    fn rate_window(&self, video_bitrate: f32) -> (Option<f32>, Option<f32>) {
+        (
+            Some(video_bitrate * MIN_RATE_FACTOR),
+            Some(video_bitrate * MAX_RATE_FACTOR),
+        )
+    }
+    /// Level the output is signalled as, whose limits planning has to stay within
+    fn level(&self) -> Option<Level> {
+        None
+    }
+}
+
+/// Decoding limits of a codec level. Hardware decoders refuse anything over them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    /// 16x16 macroblocks in a frame
+    pub frame_macroblocks: u32,
+    /// Macroblocks a second
+    pub macroblock_rate: u32,
+    pub max_bitrate: f32, //kbits
+    pub max_buffer: f32,  //kbits
+}
+
+/// H.264 High profile at level 4.1, its bitrate and buffer limits are 1.25x the baseline ones
+const HIGH_4_1: Level = Level {
+    frame_macroblocks: 8192,
+    macroblock_rate: 245_760,
+    max_bitrate: 62_500.,
+    max_buffer: 78_125.,
+};
+
+impl Level {
+    /// Tallest height up to `height` a frame of `aspect` (width / height) fits in, with the
+    /// width rounded to even like `scale=-2:<height>` does
+    pub fn fit_height(&self, height: u16, aspect: f32) -> u16 {
+        let mut height = height;
+        while height > 2 && self.macroblocks(aspect, height) > self.frame_macroblocks {
+            height -= 2 - height % 2;
+        }
+        height
+    }
+
+    /// Most frames a second of `aspect` and `height` that can be decoded, in whole frames
+    pub fn max_fps(&self, aspect: f32, height: u16) -> f32 {
+        (self.macroblock_rate as f32 / self.macroblocks(aspect, height) as f32).floor()
+    }
+
+    fn macroblocks(&self, aspect: f32, height: u16) -> u32 {
+        let width = (aspect * height as f32 / 2.).round() as u32 * 2;
+        width.div_ceil(16) * u32::from(height).div_ceil(16)
+    }
+}
+
+impl VideoCodec {
+    pub fn profile(&self) -> &'static dyn CodecProfile {
+        match self {
+            VideoCodec::WEBM => &Vp9,
+            VideoCodec::HEVC => &Hevc,
+            VideoCodec::AV1 => &Av1 { mp4: false },
+            VideoCodec::AV1MP4 => &Av1 { mp4: true },
+            VideoCodec::H264 => &H264,
+        }
+    }
+}
+
+impl AudioCodec {
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            AudioCodec::OPUS => "libopus",
+            AudioCodec::OPUSNATIVE => "opus",
+            AudioCodec::VORBIS => "libvorbis",
+            AudioCodec::AAC => "aac",
+        }
+    }
+
+    /// `-acodec` and whatever else the encoder needs
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["-acodec".into(), self.encoder().into()];
+        // ffmpegs own opus encoder is still marked experimental
+        if let AudioCodec::OPUSNATIVE = self {
+            args.extend(["-strict".into(), "-2".into()]);
+        }
+        args
+    }
+
+    /// Whether it can go in a video with the container `extension`
+    pub fn fits(&self, extension: &str) -> bool {
+        !matches!(
+            (self, extension),
+            (AudioCodec::VORBIS, "mp4") | (AudioCodec::AAC, "webm")
+        )
+    }
+
+    /// Container extension for audio only outputs
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioCodec::OPUS | AudioCodec::OPUSNATIVE | AudioCodec::VORBIS => "ogg",
+            AudioCodec::AAC => "m4a",
+        }
+    }
+}
+
+/// Average bitrate, with a window the encoder may move around in
+fn bitrate_args(video_bitrate: f32) -> Vec<String> {
+    vec![
+        "-b:v".into(),
+        format!("{}k", video_bitrate as u32),
+        "-minrate".into(),
+        format!("{}k", (video_bitrate * MIN_RATE_FACTOR) as u32),
+        "-maxrate".into(),
+        format!("{}k", (video_bitrate * MAX_RATE_FACTOR) as u32),
+    ]
+}
+
+/// ffmpegs own `-pass`/`-passlogfile` handling, used by libvpx, libaom and libx264
+fn libav_pass_args(settings: &PassSettings) -> Vec<String> {
+    vec![
+        "-pass".into(),
+        settings.pass.to_string(),
+        "-passlogfile".into(),
+        settings.passlogfile.to_string_lossy().into_owned(),
+    ]
+}
+
+/// Owned ffmpeg arguments out of string literals
+pub(crate) fn to_args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+struct Vp9;
+
+impl CodecProfile for Vp9 {
+    fn video_encoder(&self) -> &'static str {
+        "libvpx-vp9"
+    }
+    fn audio_codec(&self) -> AudioCodec {
+        AudioCodec::OPUS
+    }
+    fn extension(&self) -> &'static str {
+        "webm"
+    }
+    fn video_args(&self, settings: &PassSettings) -> Vec<String> {
+        let mut args = bitrate_args(settings.video_bitrate);
+        args.extend(to_args(&[
+            "-deadline",
+            "good",
+            "-quality",
+            "good",
+            "-cpu-used",
+            "0",
+            "-undershoot-pct",
+            "0",
+            "-overshoot-pct",
+            "0",
+            "-row-mt",
+            "1",
+            "-tile-rows",
+            "2",
+            "-tile-columns",
+            "4",
+            "-auto-alt-ref",
+            "6",
+            "-qmax",
+            "60",
+            "-qmin",
+            "1",
+            "-g",
+            "240",
+        ]));
+        args.extend(["-threads".into(), settings.threads.to_string()]);
+        args.extend(libav_pass_args(settings));
+        args
+    }
+}
+
+struct Hevc;
+
+impl CodecProfile for Hevc {
+    fn video_encoder(&self) -> &'static str {
+        "libx265"
+    }
+    fn audio_codec(&self) -> AudioCodec {
+        AudioCodec::AAC
+    }
+    fn extension(&self) -> &'static str {
+        "mp4"
+    }
+    /// x265 ignores `-pass`/`-passlogfile`, two-pass and threading go through `-x265-params`
+    fn video_args(&self, settings: &PassSettings) -> Vec<String> {
+        let bitrate = settings.video_bitrate as u32;
+        let mut stats = settings.passlogfile.as_os_str().to_owned();
+        stats.push("-x265.log");
+        let mut args = to_args(&["-preset", "slow", "-tag:v", "hvc1"]);
+        args.extend([
+            "-b:v".into(),
+            format!("{bitrate}k"),
+            "-x265-params".into(),
+            format!(
+                "pass={}:stats={}:vbv-maxrate={}:vbv-bufsize={}:keyint=240:pools={}",
+                settings.pass,
+                stats.to_string_lossy(),
+                (settings.video_bitrate * MAX_RATE_FACTOR) as u32,
+                bitrate * 2,
+                settings.threads
+            ),
+        ]);
+        args
+    }
+    fn rate_window(&self, video_bitrate: f32) -> (Option<f32>, Option<f32>) {
+        (None, Some(video_bitrate * MAX_RATE_FACTOR))
+    }
+}
+
+struct Av1 {
+    mp4: bool,
+}
+
+impl CodecProfile for Av1 {
+    fn video_encoder(&self) -> &'static str {
+        "libaom-av1"
+    }
+    fn audio_codec(&self) -> AudioCodec {
+        AudioCodec::OPUS
+    }
+    fn extension(&self) -> &'static str {
+        match self.mp4 {
+            true => "mp4",
+            false => "webm",
+        }
+    }
+    fn video_args(&self, settings: &PassSettings) -> Vec<String> {
+        let mut args = bitrate_args(settings.video_bitrate);
+        // cpu-used 0 would take ages with libaom, 4 is a sane speed/quality tradeoff
+        args.extend(to_args(&[
+            "-cpu-used",
+            "4",
+            "-undershoot-pct",
+            "0",
+            "-overshoot-pct",
+            "0",
+            "-row-mt",
+            "1",
+            "-tile-rows",
+            "1",
+            "-tile-columns",
+            "2",
+            "-auto-alt-ref",
+            "1",
+            "-lag-in-frames",
+            "35",
+            "-qmax",
+            "63",
+            "-qmin",
+            "1",
+            "-g",
+            "240",
+        ]));
+        args.extend(["-threads".into(), settings.threads.to_string()]);
+        args.extend(libav_pass_args(settings));
+        args
+    }
+}
+
+struct H264;
+
+impl CodecProfile for H264 {
+    fn video_encoder(&self) -> &'static str {
+        "libx264"
+    }
+    fn audio_codec(&self) -> AudioCodec {
+        AudioCodec::AAC
+    }
+    fn extension(&self) -> &'static str {
+        "mp4"
+    }
+    fn video_args(&self, settings: &PassSettings) -> Vec<String> {
+        let bitrate = settings.video_bitrate as u32;
+        let (_, maxrate) = self.rate_window(settings.video_bitrate);
+        let bufsize = (settings.video_bitrate * 2.).min(HIGH_4_1.max_buffer);
+        let mut args = vec![
+            "-b:v".into(),
+            format!("{bitrate}k"),
+            "-maxrate".into(),
+            format!("{}k", maxrate.unwrap_or(settings.video_bitrate) as u32),
+            "-bufsize".into(),
+            format!("{}k", bufsize as u32),
+        ];
+        // high@4.1 + yuv420p is what older iPhones and windows' built in players decode
+        args.extend(to_args(&[
+            "-preset",
+            "slow",
+            "-profile:v",
+            "high",
+            "-level:v",
+            "4.1",
+            "-pix_fmt",
+            "yuv420p",
+            "-g",
+            "240",
+        ]));
+        args.extend(["-threads".into(), settings.threads.to_string()]);
+        args.extend(libav_pass_args(settings));
+        args
+    }
+    fn rate_window(&self, video_bitrate: f32) -> (Option<f32>, Option<f32>) {
+        let max = (video_bitrate * MAX_RATE_FACTOR).min(HIGH_4_1.max_bitrate);
+        (None, Some(max))
+    }
+    fn level(&self) -> Option<Level> {
+        Some(HIGH_4_1)
+    }
+}
@@ -0,0 +1,65 @@
+use n_mb::executor::{Event, JobResult, JobStatus};
+use n_mb::plan::MediaType;
+use serde::Serialize;
+use std::path::Path;
+
+/// One line of `--progress ndjson`
+pub fn print_event(event: &Event) {
+    println!(
+        "{}",
+        serde_json::to_string(event).expect("events always serialize")
+    );
+}
+
+/// One entry of `--report json`
+#[derive(Serialize)]
+struct JobReport<'a> {
+    input: &'a Path,
+    output: &'a Path,
+    media_type: MediaType,
+    codec: &'a str,
+    status: &'static str,
+    error: Option<&'a str>,
+    input_size: Option<u64>,    //bytes
+    output_size: Option<u64>,   //bytes
+    target_size: u64,           //bytes
+    video_bitrate: Option<u32>, //kbits
+    audio_bitrate: Option<u32>, //kbits
+    size_attempts: u8,
+    wall_time: Option<f32>, //secs
+}
+
+/// `--report json`, a summary of every job once they're all done, on a single line of stdout
+pub fn print_report(results: &[JobResult]) {
+    let reports = results
+        .iter()
+        .map(|result| {
+            let job = &result.job;
+            let (video_bitrate, audio_bitrate) = job.bitrates();
+            let (status, error) = match &result.status {
+                JobStatus::Finished => ("finished", None),
+                JobStatus::Failed(error) => ("failed", Some(error.as_str())),
+                JobStatus::Cancelled => ("cancelled", None),
+            };
+            JobReport {
+                input: job.input(),
+                output: job.output(),
+                media_type: job.media_type(),
+                codec: job.codec(),
+                status,
+                error,
+                input_size: std::fs::metadata(job.input()).ok().map(|m| m.len()),
+                output_size: result.output_size,
+                target_size: job.target_size().bytes(),
+                video_bitrate: video_bitrate.map(|b| b as u32),
+                audio_bitrate: audio_bitrate.map(|b| b as u32),
+                size_attempts: job.size_attempts(),
+                wall_time: result.elapsed.map(|e| e.as_secs_f32()),
+            }
+        })
+        .collect::<Vec<_>>();
+    println!(
+        "{}",
+        serde_json::to_string(&reports).expect("reports always serialize")
+    );
+}
@@ -0,0 +1,136 @@
+use std::{fmt, str::FromStr};
+
+/// Upload limits of common platforms, usable by name in `--size`
+pub const PRESETS: [(&str, TargetSize); 5] = [
+    ("discord", TargetSize::from_bytes(10 * MB)),
+    ("discord-nitro", TargetSize::from_bytes(500 * MB)),
+    ("telegram", TargetSize::from_bytes(2 * GB)),
+    ("whatsapp", TargetSize::from_bytes(16 * MB)),
+    ("email", TargetSize::from_bytes(25 * MB)),
+];
+
+const KB: u64 = 1000;
+const MB: u64 = 1000 * KB;
+const GB: u64 = 1000 * MB;
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+
+/// The size an output has to fit under
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TargetSize {
+    bytes: u64,
+}
+
+impl TargetSize {
+    pub const fn from_bytes(bytes: u64) -> Self {
+        TargetSize { bytes }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Size in kilobits, the unit ffmpeg bitrates are given in
+    pub fn kbits(&self) -> f32 {
+        self.bytes as f32 * 8. / 1000.
+    }
+}
+
+/// Parses `500KB`, `9.5MB`, `10MiB`, `2GB` or a preset name. Plain numbers are megabytes.
+impl FromStr for TargetSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        if let Some((_, size)) = PRESETS.iter().find(|(name, _)| *name == s) {
+            return Ok(*size);
+        }
+
+        let unit_start = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(unit_start);
+        let number = number
+            .parse::<f64>()
+            .map_err(|_| format!("`{s}` is neither a size nor a known preset"))?;
+        let unit = match unit.trim() {
+            "b" => 1,
+            "k" | "kb" => KB,
+            "" | "m" | "mb" => MB,
+            "g" | "gb" => GB,
+            "kib" => KIB,
+            "mib" => MIB,
+            "gib" => GIB,
+            other => return Err(format!("unknown size unit `{other}`")),
+        };
+
+        let bytes = number * unit as f64;
+        if !bytes.is_finite() || bytes < 1. || bytes > u64::MAX as f64 {
+            return Err(format!("`{s}` is not a usable size"));
+        }
+        Ok(TargetSize::from_bytes(bytes as u64))
+    }
+}
+
+impl fmt::Display for TargetSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bytes {
+            b if b >= GB => write!(f, "{}GB", b as f64 / GB as f64),
+            b if b >= MB => write!(f, "{}MB", b as f64 / MB as f64),
+            b if b >= KB => write!(f, "{}KB", b as f64 / KB as f64),
+            b => write!(f, "{b}B"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> TargetSize {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn units_and_plain_megabytes() {
+        assert_eq!(parse("500KB").bytes(), 500_000);
+        assert_eq!(parse("9.5MB").bytes(), 9_500_000);
+        assert_eq!(parse("10MiB").bytes(), 10 * 1024 * 1024);
+        assert_eq!(parse("2GB").bytes(), 2_000_000_000);
+        assert_eq!(parse("25").bytes(), 25_000_000);
+        assert_eq!(parse(" 9.5 mb ").bytes(), 9_500_000);
+    }
+
+    #[test]
+    fn presets_by_name() {
+        for (name, size) in PRESETS {
+            assert_eq!(parse(name), size, "{name}");
+            assert_eq!(parse(&name.to_uppercase()), size, "{name}");
+        }
+        assert_eq!(parse("discord").bytes(), 10_000_000);
+    }
+
+    #[test]
+    fn unusable_sizes_are_errors() {
+        for s in ["0", "-1", "abc", "5XB", "", "0.0000001B"] {
+            assert!(s.parse::<TargetSize>().is_err(), "{s}");
+        }
+        assert_eq!(
+            "5XB".parse::<TargetSize>().unwrap_err(),
+            "unknown size unit `xb`"
+        );
+    }
+
+    #[test]
+    fn display_parses_back_to_the_same_size() {
+        for bytes in [1, 999, 500_000, 9_500_000, 10 * 1024 * 1024, 2_000_000_000] {
+            let size = TargetSize::from_bytes(bytes);
+            assert_eq!(parse(&size.to_string()), size, "{size}");
+        }
+        assert_eq!(parse("9.5MB").to_string(), "9.5MB");
+        assert_eq!(parse("500KB").to_string(), "500KB");
+        assert_eq!(parse("2GB").to_string(), "2GB");
+        assert_eq!(TargetSize::from_bytes(999).to_string(), "999B");
+    }
+}
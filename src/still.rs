@@ -0,0 +1,216 @@
+use anyhow::{anyhow, Context};
+use image::codecs::{avif::AvifEncoder, jpeg::JpegEncoder};
+use image::{imageops::FilterType, DynamicImage, ImageDecoder, ImageReader};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::mpsc;
+
+use crate::backend::{BlockingPass, Progress};
+use crate::ImageFormat;
+
+/// libwebp's slowest and smallest method, what `-compression_level 6` picks in ffmpeg
+const WEBP_METHOD: i32 = 6;
+/// rav1e speed from 1 (slowest) to 10, anything lower takes seconds per megapixel
+const AVIF_SPEED: u8 = 6;
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::WEBP => "webp",
+            Self::AVIF => "avif",
+            Self::JPEG => "jpg",
+        }
+    }
+
+    /// Library doing the encoding, what [`crate::plan::Job::codec`] reports
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            Self::WEBP => "libwebp",
+            Self::AVIF => "rav1e",
+            Self::JPEG => "jpeg",
+        }
+    }
+}
+
+/// Whether nmb decodes pictures of `codec_name` (as probing reports it) itself. Everything
+/// else goes through ffmpeg.
+pub fn decodes(codec_name: &str) -> bool {
+    matches!(codec_name, "png" | "mjpeg" | "tiff" | "bmp" | "webp")
+}
+
+/// One attempt at encoding a still image in-process, see [`crate::plan::Job::still`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Still {
+    pub output: PathBuf,
+    pub format: ImageFormat,
+    /// From 0 to 100
+    pub quality: u8,
+    pub scale: f32,
+}
+
+/// A decoded picture, upright, shared by every attempt of a job so it's only decoded once
+#[derive(Clone)]
+pub struct Source(Arc<DynamicImage>);
+
+impl Source {
+    pub async fn decode(path: &Path) -> anyhow::Result<Self> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || decode(&path))
+            .await
+            .context("Decoding panicked")?
+            .map(|image| Source(Arc::new(image)))
+    }
+
+    /// Starts encoding `still` on a blocking thread. There's no time to report, so `out_time`
+    /// is the share of the encode that's done, going from 0 to 1.
+    pub fn start(&self, still: Still) -> BlockingPass {
+        let image = self.0.clone();
+        BlockingPass::spawn(
+            move |progress, cancelled| encode(&image, &still, progress, cancelled),
+            Err,
+        )
+    }
+}
+
+fn decode(path: &Path) -> anyhow::Result<DynamicImage> {
+    let mut decoder = ImageReader::open(path)
+        .with_context(|| format!("Can't open {}", path.display()))?
+        .with_guessed_format()?
+        .into_decoder()
+        .context("Can't read the image")?;
+    // ffmpeg ignores the exif orientation, but whatever shows the output won't find it anymore
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder).context("Can't decode the image")?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+fn encode(
+    image: &DynamicImage,
+    still: &Still,
+    progress: &mpsc::UnboundedSender<Progress>,
+    cancelled: &AtomicBool,
+) -> anyhow::Result<()> {
+    let report = |done: f32, size: usize| {
+        let _ = progress.send(Progress {
+            out_time: done,
+            speed: None,
+            size: size as u64,
+        });
+    };
+    let check_cancelled = || match cancelled.load(Ordering::Relaxed) {
+        true => Err(anyhow!("Cancelled")),
+        false => Ok(()),
+    };
+
+    report(0., 0);
+    let resized;
+    let image = match still.scale < 1. {
+        true => {
+            let width = ((image.width() as f32 * still.scale) as u32).max(1);
+            let height = ((image.height() as f32 * still.scale) as u32).max(1);
+            resized = image.resize_exact(width, height, FilterType::Lanczos3);
+            report(0.3, 0);
+            &resized
+        }
+        false => image,
+    };
+    check_cancelled()?;
+
+    // 8 bits per channel is all the encoders take, and jpeg has no alpha
+    let alpha = image.color().has_alpha() && still.format != ImageFormat::JPEG;
+    let pixels = match alpha {
+        true => DynamicImage::ImageRgba8(image.to_rgba8()),
+        false => DynamicImage::ImageRgb8(image.to_rgb8()),
+    };
+    let (width, height) = (pixels.width(), pixels.height());
+    let mut encoded = vec![];
+    match still.format {
+        ImageFormat::WEBP => {
+            let encoder = match alpha {
+                true => webp::Encoder::from_rgba(pixels.as_bytes(), width, height),
+                false => webp::Encoder::from_rgb(pixels.as_bytes(), width, height),
+            };
+            let mut config =
+                webp::WebPConfig::new().map_err(|_| anyhow!("libwebp has no default config"))?;
+            config.quality = f32::from(still.quality);
+            config.method = WEBP_METHOD;
+            let webp = encoder
+                .encode_advanced(&config)
+                .map_err(|e| anyhow!("libwebp failed: {e:?}"))?;
+            encoded.extend_from_slice(&webp);
+        }
+        ImageFormat::AVIF => pixels
+            .write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut encoded,
+                AVIF_SPEED,
+                still.quality,
+            ))
+            .context("Can't encode AVIF")?,
+        ImageFormat::JPEG => pixels
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, still.quality))
+            .context("Can't encode JPEG")?,
+    }
+    report(0.9, encoded.len());
+    check_cancelled()?;
+
+    std::fs::write(&still.output, &encoded)
+        .with_context(|| format!("Can't write {}", still.output.display()))?;
+    report(1., encoded.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Pass;
+    use crate::probe::native;
+    use image::{Rgb, RgbImage};
+
+    fn gradient() -> Source {
+        let image = RgbImage::from_fn(64, 48, |x, y| Rgb([(x * 4) as u8, (y * 5) as u8, 128]));
+        Source(Arc::new(DynamicImage::ImageRgb8(image)))
+    }
+
+    #[tokio::test]
+    async fn every_format_encodes_scaled_and_reads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        for (format, codec) in [
+            (ImageFormat::WEBP, "webp"),
+            (ImageFormat::AVIF, "av1"),
+            (ImageFormat::JPEG, "mjpeg"),
+        ] {
+            let output = dir.path().join(format!("out.{}", format.extension()));
+            let mut pass = gradient().start(Still {
+                output: output.clone(),
+                format,
+                quality: 50,
+                scale: 0.5,
+            });
+            let mut done = vec![];
+            while let Some(progress) = pass.progress().await {
+                done.push(progress.out_time);
+            }
+            assert!(pass.wait().await.unwrap().success(), "{format}");
+            assert_eq!(done, [0., 0.3, 0.9, 1.], "{format}");
+
+            let probe = native::probe(&output).unwrap();
+            let video = probe.video().unwrap();
+            assert_eq!(video.codec_name, codec, "{format}");
+            assert_eq!(video.resolution(), Some((32, 24)), "{format}");
+        }
+    }
+
+    #[test]
+    fn only_formats_the_image_crate_reads_are_decoded_in_process() {
+        for codec in ["png", "mjpeg", "tiff", "bmp", "webp"] {
+            assert!(decodes(codec), "{codec}");
+        }
+        for codec in ["hevc", "av1", "jpegxl", "gif", "apng", "h264"] {
+            assert!(!decodes(codec), "{codec}");
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::plan::Constraints;
+use crate::size::TargetSize;
+use crate::{AudioCodec, ImageFormat, VideoCodec};
+
+const BUILTIN_TARGETS: &str = include_str!("targets.toml");
+
+/// Everything an upload platform limits, not just the file size
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub name: String,
+    pub size: TargetSize,
+    pub codec: VideoCodec,
+    pub audio_codec: Option<AudioCodec>,
+    pub max_height: Option<u16>,
+    pub max_fps: Option<f32>,
+    pub max_duration: Option<f32>, //secs
+}
+
+#[derive(Deserialize)]
+struct TargetsFile {
+    #[serde(default)]
+    target: Vec<RawTarget>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTarget {
+    name: String,
+    size: String,
+    codec: String,
+    audio_codec: Option<String>,
+    max_height: Option<u16>,
+    max_fps: Option<f32>,
+    max_duration: Option<f32>,
+}
+
+impl Target {
+    pub fn constraints(&self, split: bool) -> Constraints {
+        Constraints {
+            size: self.size,
+            codec: self.codec.clone(),
+            audio_codec: self.audio_codec,
+            max_height: self.max_height,
+            max_fps: self.max_fps,
+            max_duration: self.max_duration,
+            split,
+            image_format: ImageFormat::WEBP,
+        }
+    }
+}
+
+impl TryFrom<RawTarget> for Target {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawTarget) -> anyhow::Result<Self> {
+        let size = TargetSize::from_str(&raw.size)
+            .map_err(|e| anyhow::anyhow!("target `{}`: {e}", raw.name))?;
+        let codec = VideoCodec::from_string(&raw.codec)
+            .with_context(|| format!("target `{}`: unknown codec `{}`", raw.name, raw.codec))?;
+        let audio_codec = match &raw.audio_codec {
+            Some(a) => Some(
+                AudioCodec::from_string(a)
+                    .with_context(|| format!("target `{}`: unknown audio codec `{a}`", raw.name))?,
+            ),
+            None => None,
+        };
+        let extension = codec.profile().extension();
+        if let Some(audio_codec) = audio_codec.filter(|a| !a.fits(extension)) {
+            bail!(
+                "target `{}`: {} audio doesn't go in .{extension}, which {} outputs",
+                raw.name,
+                audio_codec.encoder(),
+                codec
+            );
+        }
+        Ok(Target {
+            name: raw.name,
+            size,
+            codec,
+            audio_codec,
+            max_height: raw.max_height,
+            max_fps: raw.max_fps,
+            max_duration: raw.max_duration,
+        })
+    }
+}
+
+fn parse_targets(text: &str) -> anyhow::Result<Vec<Target>> {
+    toml::from_str::<TargetsFile>(text)?
+        .target
+        .into_iter()
+        .map(Target::try_from)
+        .collect()
+}
+
+/// Where user defined targets get picked up from when `--target-config` isn't given
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+    };
+    config_dir.map(|d| d.join("nmb").join("targets.toml"))
+}
+
+/// Built in targets, with the ones from `config` added on top. Targets with the same name
+/// as a built in one replace it. A missing `config` is only an error if it was asked for
+/// explicitly.
+pub fn load_targets(config: Option<&Path>) -> anyhow::Result<Vec<Target>> {
+    let mut targets = parse_targets(BUILTIN_TARGETS).context("built in targets are broken")?;
+
+    let path = match config {
+        Some(path) => Some(path.to_path_buf()),
+        None => default_config_path().filter(|p| p.exists()),
+    };
+    if let Some(path) = path {
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Can't read target config {}", path.display()))?;
+        let user_targets = parse_targets(&text)
+            .with_context(|| format!("Invalid target config {}", path.display()))?;
+        for target in user_targets {
+            targets.retain(|t| t.name != target.name);
+            targets.push(target);
+        }
+    }
+    Ok(targets)
+}
+
+pub fn find_target(targets: Vec<Target>, name: &str) -> anyhow::Result<Target> {
+    let name = name.to_lowercase();
+    let names = targets
+        .iter()
+        .map(|t| t.name.clone())
+        .collect::<Vec<_>>()
+        .join(", ");
+    match targets.into_iter().find(|t| t.name.to_lowercase() == name) {
+        Some(target) => Ok(target),
+        None => bail!("Unknown target `{name}`, available targets: {names}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_targets_parse() {
+        let targets = parse_targets(BUILTIN_TARGETS).unwrap();
+        assert!(!targets.is_empty());
+    }
+
+    #[test]
+    fn audio_has_to_fit_the_container() {
+        let target = |codec: &str, audio_codec: &str| {
+            parse_targets(&format!(
+                r#"
+                [[target]]
+                name = "x"
+                size = "8MB"
+                codec = "{codec}"
+                audio_codec = "{audio_codec}"
+                "#
+            ))
+        };
+        assert_eq!(
+            target("webm", "aac").unwrap_err().to_string(),
+            "target `x`: aac audio doesn't go in .webm, which WEBM outputs"
+        );
+        assert!(target("h264", "vorbis").is_err());
+        let target = target("h264", "aac").unwrap();
+        assert_eq!(target[0].audio_codec, Some(AudioCodec::AAC));
+    }
+}
@@ -1,95 +1,216 @@
-use crate::encoder::{EncodingStatus, FFMPEGCommand, MediaType};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::sync::Arc;
+use n_mb::backend::Ffmpeg;
+use n_mb::executor::Event;
+use n_mb::plan::{ImageSettings, Job, MAX_SIZE_ATTEMPTS};
 use std::time::Duration;
-use tokio::sync::Mutex;
-use tokio::time::interval;
+use tokio::process::Command;
 
-pub async fn display(commands: Arc<Mutex<Vec<FFMPEGCommand>>>) {
-    let mb = MultiProgress::new();
-    let sty = ProgressStyle::with_template(
-        "{spinner:.blue} {msg} [{elapsed_precise}/{eta_precise}(eta)] {bar:40.cyan/blue} {pos:>7}/{len:7} (ms)",
-    )
-    .unwrap()
-    .tick_strings(&[
-        "▏", "▎", "▍", "▌", "▋", "▉", "█", "█", "▉", "▊", "▋", "▌", "▍", "▎", "▏",
-    ]);
-    //.progress_chars("##-");
+/// Progress bars on stderr, one per job, driven by the executors events
+pub struct Bars {
+    bars: Vec<Option<ProgressBar>>,
+    labels: Vec<String>,
+    _multi: MultiProgress,
+}
+
+impl Bars {
+    pub fn new(jobs: &[Job]) -> Self {
+        let multi = MultiProgress::new();
+        let sty = ProgressStyle::with_template(
+            "{spinner:.blue} {msg} [{elapsed_precise}/{eta_precise}(eta)] {bar:40.cyan/blue} {pos:>7}/{len:7} (ms)",
+        )
+        .unwrap()
+        .tick_strings(&[
+            "▏", "▎", "▍", "▌", "▋", "▉", "█", "█", "▉", "▊", "▋", "▌", "▍", "▎", "▏",
+        ]);
 
-    let mut pbs = vec![];
-    for (i, command) in commands.lock().await.iter_mut().enumerate() {
-        if let Some(dur) = command.duration {
-            let pb = mb.add(ProgressBar::new((dur * 100.) as u64));
-            pb.set_style(sty.clone());
-            pb.set_message("Starting : ");
-            pb.tick();
-            pbs.push((i, pb));
+        let bars = jobs
+            .iter()
+            .map(|job| {
+                let pb = match job.duration() {
+                    Some(dur) => multi.add(ProgressBar::new((dur * 100.) as u64)),
+                    // in-process stills report each attempt going from 0 to 1
+                    None if job.still().is_some() => multi.add(ProgressBar::new(100)),
+                    // images ffmpeg encodes have no duration, the bar just tracks the size search
+                    None if job.image_settings().is_some() => multi.add(ProgressBar::new(1)),
+                    _ => return None,
+                };
+                pb.set_style(sty.clone());
+                pb.set_message("Starting : ");
+                pb.enable_steady_tick(Duration::from_millis(50));
+                Some(pb)
+            })
+            .collect();
+        Bars {
+            bars,
+            labels: jobs.iter().map(|j| j.label().to_owned()).collect(),
+            _multi: multi,
         }
     }
-    let mut spawns = vec![];
-    for pb in pbs.into_iter() {
-        let commands_mut = commands.clone();
-        spawns.push(tokio::spawn(async move {
-            let pr = pb.1;
-            let mut intv = interval(Duration::from_millis(50));
-            loop {
-                intv.tick().await;
-                let command = commands_mut.lock().await;
-                let command = command
-                    .get(pb.0)
-                    .expect("command for progressbar failed to index");
-                match command.status {
-                    EncodingStatus::NotStarted => pr.set_message("Starting : "),
-                    EncodingStatus::InProgress => match command.media_type {
-                        MediaType::Video => match command.passed_pass_1 {
-                            true => {
-                                pr.set_message(command.file_name.clone() + ": Encoding (Pass 2/2)")
-                            }
-                            false => {
-                                pr.set_message(command.file_name.clone() + ": Encoding (Pass 1/2)")
-                            }
-                        },
-                        _ => pr.set_message(command.file_name.clone() + ": Encoding"),
-                    },
-                    EncodingStatus::Failed => {
-                        pr.set_message(command.file_name.clone() + ": Failed!");
-                        pr.set_position(command.duration.unwrap() as u64);
-                        pr.finish();
-                        break;
-                    }
-                    EncodingStatus::Finished => {
-                        pr.set_message(command.file_name.clone() + ": Finished!");
-                        pr.set_position(command.duration.unwrap() as u64);
-                        pr.finish();
-                        break;
+
+    pub fn update(&self, event: &Event) {
+        let job = match event {
+            Event::Queued { job, .. }
+            | Event::PassStarted { job, .. }
+            | Event::Progress { job, .. }
+            | Event::Finished { job, .. }
+            | Event::Failed { job, .. }
+            | Event::Cancelled { job } => *job,
+        };
+        let Some(Some(pr)) = self.bars.get(job) else {
+            return;
+        };
+        let name = &self.labels[job];
+        match event {
+            Event::Queued { .. } => pr.set_message(format!("{name}: Queued")),
+            Event::PassStarted {
+                pass,
+                attempt,
+                image,
+                ..
+            } => {
+                pr.set_position(0);
+                match (pass, image) {
+                    (Some(2), _) if *attempt > 1 => pr.set_message(format!(
+                        "{name}: Encoding (Pass 2/2, attempt {attempt}/{MAX_SIZE_ATTEMPTS})"
+                    )),
+                    (Some(pass), _) => pr.set_message(format!("{name}: Encoding (Pass {pass}/2)")),
+                    (None, Some(image)) => pr.set_message(format!(
+                        "{name}: Searching size ({})",
+                        image_settings(image)
+                    )),
+                    (None, None) => pr.set_message(format!("{name}: Encoding")),
+                }
+            }
+            Event::Progress { out_time, .. } => pr.set_position((out_time * 100.) as u64),
+            Event::Finished { image, .. } => {
+                match image {
+                    Some(image) => {
+                        pr.set_message(format!("{name}: Finished! ({})", image_settings(image)))
                     }
-                };
-                pr.set_position((command.progressed_time * 100.) as u64);
+                    None => pr.set_message(format!("{name}: Finished!")),
+                }
+                pr.set_position(pr.length().unwrap_or(0));
+                pr.finish();
             }
-        }));
+            Event::Failed { error, .. } => {
+                pr.set_message(format!("{name}: Failed! ({error})"));
+                pr.set_position(pr.length().unwrap_or(0));
+                pr.finish();
+            }
+            Event::Cancelled { .. } => pr.abandon_with_message(format!("{name}: Cancelled")),
+        }
     }
+}
 
-    for spawn in spawns {
-        if let Ok(_) = spawn.await {};
+/// Quality and resolution the image size search is at
+fn image_settings(image: &ImageSettings) -> String {
+    let mut settings = match image.resolution {
+        Some((w, h)) => format!("quality {}, {w}x{h}", image.quality),
+        None => format!(
+            "quality {}, scale {:.0}%",
+            image.quality,
+            image.scale * 100.
+        ),
+    };
+    if image.frame_step > 1 {
+        settings += &format!(", 1/{} frames", image.frame_step);
     }
-    /*
-    pb.tick_format("▏▎▍▌▋▊▉██▉▊▋▌▍▎▏");
-    pb.show_message = true;
-    pb.message("Waiting  : ");
-    pb.tick();
-    pb.message("Connected: ");
+    settings
+}
 
-    pb.inc();
+/// What `--dry-run` prints: everything that was decided for each job, and the ffmpeg
+/// command lines that would run, if ffmpeg does the job
+pub fn print_plan(jobs: &[Job], ffmpeg: &Ffmpeg) -> anyhow::Result<()> {
+    for job in jobs {
+        let duration = job
+            .duration()
+            .map_or(String::new(), |d| format!(", {d:.1}s"));
+        println!("{} ({:?}{duration})", job.label(), job.media_type());
+        println!("  output: {}", job.output().display());
 
-    pb.message("Cleaning :");
-    pb.tick();
-    pb.finish_print(&format!(": Pull complete",));
-    mb.println("");
-    mb.println("Text lines separate between two sections: ");
-    mb.println("");
+        let (video_bitrate, audio_bitrate) = job.bitrates();
+        if let Some(height) = job.height() {
+            let fps = job
+                .fps_cap()
+                .map_or(String::new(), |fps| format!(", capped to {fps}fps"));
+            println!("  height: {height}p{fps}");
+        }
+        let mut rates = vec![];
+        if let Some(bitrate) = video_bitrate {
+            let (min, max) = job.rate_window();
+            let mut rate = format!("video {}k", bitrate as u32);
+            if let Some(min) = min {
+                rate += &format!(", min {}k", min as u32);
+            }
+            if let Some(max) = max {
+                rate += &format!(", max {}k", max as u32);
+            }
+            rates.push(rate);
+        }
+        if let Some(bitrate) = audio_bitrate {
+            rates.push(format!("audio {}k", bitrate as u32));
+        }
+        if !rates.is_empty() {
+            println!("  bitrates: {}", rates.join(", "));
+        }
+
+        let target_mb = job.target_size().bytes() as f64 / 1_000_000.;
+        match (
+            job.duration(),
+            video_bitrate.unwrap_or(0.) + audio_bitrate.unwrap_or(0.),
+        ) {
+            (Some(duration), kbits) if kbits > 0. => println!(
+                "  expected size: {:.2}MB of {target_mb}MB",
+                (kbits * duration) as f64 * 1000. / 8. / 1_000_000.
+            ),
+            _ => println!("  expected size: searched for, up to {target_mb}MB"),
+        }
 
-    mb.listen();
+        match job.passes() {
+            1 if let Some(still) = job.still() => println!(
+                "  first attempt: in-process {} at quality {}",
+                still.format, still.quality
+            ),
+            1 if job.image_settings().is_some() => {
+                println!(
+                    "  first attempt: {}",
+                    command_line(&ffmpeg.command(&job.args(1)?))
+                )
+            }
+            1 => println!(
+                "  command: {}",
+                command_line(&ffmpeg.command(&job.args(1)?))
+            ),
+            passes => {
+                for pass in 1..=passes {
+                    println!(
+                        "  pass {pass}: {}",
+                        command_line(&ffmpeg.command(&job.args(pass)?))
+                    );
+                }
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
 
-    println!("\nall bars done!\n");
-    */
+/// `command` the way it would be typed into a shell
+fn command_line(command: &Command) -> String {
+    let command = command.as_std();
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c));
+            match plain {
+                true => arg.into_owned(),
+                false => format!("'{}'", arg.replace('\'', "'\\''")),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
 }
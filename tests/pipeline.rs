@@ -0,0 +1,632 @@
+//! Probing, planning and running the bundled fixtures end to end, against the scripted
+//! [`Fake`] backend and, with `--ignored`, the real ffmpeg
+
+use n_mb::backend::{fake::Fake, Backend, Ffmpeg};
+use n_mb::capabilities::Capabilities;
+use n_mb::executor::{Event, Executor, JobResult, JobStatus};
+use n_mb::output::{Collision, Naming, OutputNaming, DEFAULT_TEMPLATE};
+use n_mb::plan::{Constraints, Job, MediaType};
+use n_mb::probe::{media_type_hint, native, Probe};
+use n_mb::size::TargetSize;
+use n_mb::{ImageFormat, VideoCodec};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+const MB: u64 = 1_000_000;
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(name)
+}
+
+fn constraints(size: TargetSize, codec: VideoCodec) -> Constraints {
+    Constraints {
+        size,
+        codec,
+        audio_codec: None,
+        max_height: None,
+        max_fps: None,
+        max_duration: None,
+        split: false,
+        image_format: ImageFormat::WEBP,
+    }
+}
+
+/// What ffprobe reports for the fixtures, trimmed to what planning looks at
+fn fixture_probe(name: &str) -> Probe {
+    let probe = match name {
+        "test.avi" => json!({
+            "streams": [{
+                "index": 0, "codec_type": "video", "codec_name": "mpeg4",
+                "width": 2560, "height": 1440, "pix_fmt": "yuv420p",
+                "r_frame_rate": "30/1", "avg_frame_rate": "30/1",
+                "duration": "1.866667", "nb_frames": "56",
+            }],
+            "format": { "format_name": "avi", "duration": "1.866667", "size": "1463092" },
+        }),
+        // matroska only has the stream durations as tags
+        "test.mkv" => json!({
+            "streams": [{
+                "index": 0, "codec_type": "video", "codec_name": "h264",
+                "width": 2560, "height": 1440, "pix_fmt": "yuv420p",
+                "r_frame_rate": "30/1", "avg_frame_rate": "30/1",
+                "tags": { "DURATION": "00:00:01.833000000" },
+            }, {
+                "index": 1, "codec_type": "audio", "codec_name": "vorbis",
+                "sample_rate": "48000", "channels": 2,
+                "r_frame_rate": "0/0", "avg_frame_rate": "0/0",
+                "tags": { "DURATION": "00:00:01.781000000" },
+            }],
+            "format": { "format_name": "matroska,webm", "duration": "1.833000", "size": "44871" },
+        }),
+        "test.jpg" => json!({
+            "streams": [{
+                "index": 0, "codec_type": "video", "codec_name": "mjpeg",
+                "width": 2560, "height": 1440, "pix_fmt": "yuvj420p",
+                "r_frame_rate": "25/1", "avg_frame_rate": "0/0",
+            }],
+            "format": { "format_name": "image2", "duration": "0.040000", "size": "212389" },
+        }),
+        _ => unreachable!("no fixture {name}"),
+    };
+    serde_json::from_value(probe).unwrap()
+}
+
+/// A fake that knows the fixtures
+fn fake() -> Fake {
+    ["test.avi", "test.mkv", "test.jpg"]
+        .into_iter()
+        .fold(Fake::new(), |fake, name| {
+            fake.with_probe(fixture(name), fixture_probe(name))
+        })
+}
+
+/// A fake whose test.jpg probes as a JPEG XL, which nmb leaves to ffmpeg
+fn fake_jpeg_xl() -> Fake {
+    let mut probe = fixture_probe("test.jpg");
+    probe.streams[0].codec_name = "jpegxl".into();
+    fake().with_probe(fixture("test.jpg"), probe)
+}
+
+/// The real ffmpeg on `PATH`, failing the test if it isn't installed
+fn system() -> Ffmpeg {
+    for bin in ["ffmpeg", "ffprobe"] {
+        let installed = std::process::Command::new(bin)
+            .arg("-version")
+            .output()
+            .is_ok_and(|o| o.status.success());
+        assert!(
+            installed,
+            "{bin} isn't installed, it's needed for this test"
+        );
+    }
+    Ffmpeg::default()
+}
+
+struct Converted {
+    jobs: Vec<JobResult>,
+    events: Vec<Event>,
+    /// Outputs and pass logs end up in here
+    dir: TempDir,
+}
+
+impl Converted {
+    fn result(&self) -> &JobResult {
+        &self.jobs[0]
+    }
+
+    /// Whatever is left in the output dir
+    fn files(&self) -> Vec<String> {
+        let mut files = std::fs::read_dir(self.dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+}
+
+/// Probes and plans the fixture `name`, then runs it to the end
+async fn convert<B: Backend>(backend: Arc<B>, name: &str, constraints: &Constraints) -> Converted {
+    let mut run = start(backend, name, constraints).await;
+    let mut events = vec![];
+    while let Some(event) = run.1.next_event().await {
+        events.push(event);
+    }
+    Converted {
+        jobs: run.1.results().await.unwrap(),
+        events,
+        dir: run.0,
+    }
+}
+
+async fn start<B: Backend>(
+    backend: Arc<B>,
+    name: &str,
+    constraints: &Constraints,
+) -> (TempDir, n_mb::executor::Run) {
+    let (dir, job) = plan(backend.as_ref(), name, constraints).await;
+    (dir, Executor::new(backend, vec![job], 1).start())
+}
+
+/// Probes and plans the fixture `name`, with outputs going to a new temp dir
+async fn plan<B: Backend>(backend: &B, name: &str, constraints: &Constraints) -> (TempDir, Job) {
+    let input = fixture(name);
+    let dir = tempfile::tempdir().unwrap();
+    let mut naming = OutputNaming::new(
+        Some(dir.path().to_path_buf()),
+        DEFAULT_TEMPLATE.to_owned(),
+        Collision::Rename,
+        &[&input],
+        dir.path().to_path_buf(),
+    )
+    .unwrap();
+    let probe = backend.probe(&input).await.unwrap();
+    let media_type = probe.media_type(media_type_hint(&input)).unwrap();
+    let job = Job::plan(media_type, &input, &probe, constraints, &mut naming)
+        .unwrap()
+        .expect("output dir is empty");
+    (dir, job)
+}
+
+/// `-b:v` of an ffmpeg run, in kbits
+fn video_bitrate(args: &[String]) -> u32 {
+    let at = args.iter().position(|a| a == "-b:v").unwrap();
+    args[at + 1].trim_end_matches('k').parse().unwrap()
+}
+
+/// Puts outputs next to their input without checking anything
+struct Beside;
+
+impl Naming for Beside {
+    fn output_path(
+        &mut self,
+        input: &Path,
+        ext: &str,
+        _: TargetSize,
+        part: Option<u16>,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let part = part.map_or(String::new(), |p| format!(".{p}"));
+        Ok(Some(input.with_extension(format!("small{part}.{ext}"))))
+    }
+
+    fn passlog_path(&mut self, output: &Path) -> PathBuf {
+        output.with_extension("log")
+    }
+}
+
+#[test]
+fn planning_needs_no_files() {
+    let input = Path::new("/nowhere/clip.mkv");
+    let constraints = constraints(TargetSize::from_bytes(10 * MB), VideoCodec::WEBM);
+    let job = Job::plan(
+        MediaType::Video,
+        input,
+        &fixture_probe("test.mkv"),
+        &constraints,
+        &mut Beside,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(job.output(), Path::new("/nowhere/clip.small.webm"));
+    assert!(job
+        .args(1)
+        .unwrap()
+        .iter()
+        .any(|a| a == "/nowhere/clip.small.log"));
+}
+
+#[tokio::test]
+async fn fixtures_probe_as_the_right_media_type() {
+    let fake = fake();
+    for (name, media_type) in [
+        ("test.avi", MediaType::Video),
+        ("test.mkv", MediaType::Video),
+        ("test.jpg", MediaType::Image),
+    ] {
+        let path = fixture(name);
+        let probe = fake.probe(&path).await.unwrap();
+        assert_eq!(
+            probe.media_type(media_type_hint(&path)).unwrap(),
+            media_type
+        );
+    }
+}
+
+#[test]
+fn native_probe_agrees_with_ffprobe_on_the_fixtures() {
+    for name in ["test.avi", "test.mkv", "test.jpg"] {
+        let path = fixture(name);
+        let native = native::probe(&path).unwrap();
+        let ffprobe = fixture_probe(name);
+        let hint = media_type_hint(&path);
+        let media_type = ffprobe.media_type(hint).unwrap();
+        assert_eq!(native.media_type(hint).unwrap(), media_type, "{name}");
+        // ffprobe gives still pictures the duration of one frame at 25fps
+        if media_type != MediaType::Image {
+            let durations = (native.duration().unwrap(), ffprobe.duration().unwrap());
+            assert!(
+                (durations.0 - durations.1).abs() < 0.01,
+                "{name}: {durations:?}"
+            );
+            let fps = |p: &Probe| p.video().and_then(|v| v.fps());
+            assert_eq!(fps(&native), fps(&ffprobe), "{name}");
+        }
+        let resolution = |p: &Probe| p.video().and_then(|v| v.resolution());
+        assert_eq!(resolution(&native), resolution(&ffprobe), "{name}");
+        // the fixture leaves out the mp3 track of test.avi
+        if let Some(audio) = ffprobe.audio() {
+            let native = native.audio().unwrap();
+            assert_eq!(native.sample_rate, audio.sample_rate, "{name}");
+            assert_eq!(native.channels, audio.channels, "{name}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn video_runs_both_passes_and_moves_the_output_into_place() {
+    let fake = Arc::new(fake());
+    let converted = convert(
+        fake.clone(),
+        "test.avi",
+        &constraints(TargetSize::from_bytes(10 * MB), VideoCodec::WEBM),
+    )
+    .await;
+
+    let result = converted.result();
+    assert_eq!(result.status, JobStatus::Finished);
+    assert_eq!(result.output_size, Some(1000));
+    assert_eq!(converted.files(), ["minified_test.webm"]);
+
+    let runs = fake.runs();
+    assert_eq!(runs.len(), 2);
+    assert!(runs[0].windows(2).any(|a| a == ["-pass", "1"]));
+    assert!(runs[1].windows(2).any(|a| a == ["-pass", "2"]));
+    assert_eq!(runs[0].last().unwrap(), "/dev/null");
+
+    let passes = converted
+        .events
+        .iter()
+        .filter_map(|e| match e {
+            Event::PassStarted { pass, .. } => Some(*pass),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(passes, [Some(1), Some(2)]);
+    assert!(matches!(converted.events[0], Event::Queued { job: 0, .. }));
+    assert!(matches!(
+        converted.events.last(),
+        Some(Event::Finished {
+            size: Some(1000),
+            ..
+        })
+    ));
+    assert!(converted
+        .events
+        .iter()
+        .any(|e| matches!(e, Event::Progress { out_time, .. } if *out_time == 1.5)));
+}
+
+#[tokio::test]
+async fn matroska_durations_come_from_the_tags() {
+    let fake = Arc::new(fake());
+    let converted = convert(
+        fake.clone(),
+        "test.mkv",
+        &constraints(TargetSize::from_bytes(10 * MB), VideoCodec::H264),
+    )
+    .await;
+    let result = converted.result();
+    assert_eq!(result.status, JobStatus::Finished);
+    assert_eq!(result.job.duration(), Some(1.833));
+    assert_eq!(result.job.codec(), "libx264+aac");
+    assert_eq!(converted.files(), ["minified_test.mp4"]);
+    // the 1440p source is over what level 4.1 decoders take
+    assert_eq!(result.job.height(), Some(1080));
+    assert!(fake.runs()[0]
+        .iter()
+        .any(|a| a.starts_with("scale=-2:1080")));
+}
+
+/// Plans test.mkv as if it was `width`x`height` at `fps`, as h264
+async fn plan_h264_as(width: u16, height: u16, fps: u16, size: TargetSize) -> Job {
+    let source = serde_json::from_value(json!({
+        "streams": [{
+            "index": 0, "codec_type": "video", "codec_name": "h264",
+            "width": width, "height": height, "pix_fmt": "yuv420p",
+            "r_frame_rate": format!("{fps}/1"), "avg_frame_rate": format!("{fps}/1"),
+        }],
+        "format": { "format_name": "matroska,webm", "duration": "1.833000", "size": "44871" },
+    }))
+    .unwrap();
+    let fake = fake().with_probe(fixture("test.mkv"), source);
+    plan(&fake, "test.mkv", &constraints(size, VideoCodec::H264))
+        .await
+        .1
+}
+
+#[tokio::test]
+async fn h264_stays_within_level_4_1() {
+    let size = TargetSize::from_bytes(10 * MB);
+    // an ultrawide 1080p is 10880 macroblocks a frame, 8192 is the most
+    let ultrawide = plan_h264_as(2560, 1080, 30, size).await;
+    assert_eq!(ultrawide.height(), Some(930));
+    assert_eq!(ultrawide.fps_cap(), None);
+    // a portrait 1080p fits as is
+    let portrait = plan_h264_as(1080, 1920, 30, size).await;
+    assert_eq!(portrait.height(), Some(1920));
+    // 1080p60 is twice the macroblocks a second it allows
+    let smooth = plan_h264_as(1920, 1080, 60, size).await;
+    assert_eq!(smooth.height(), Some(1080));
+    assert_eq!(smooth.fps_cap(), Some(30.));
+
+    // a 2GB target on a 2s clip would be hundreds of Mbps
+    let huge = plan_h264_as(1920, 1080, 30, "telegram".parse().unwrap()).await;
+    assert_eq!(huge.bitrates().0, Some(62_500.));
+    assert_eq!(huge.rate_window(), (None, Some(62_500.)));
+}
+
+#[tokio::test]
+async fn hevc_keeps_the_source_height() {
+    let converted = convert(
+        Arc::new(fake()),
+        "test.mkv",
+        &constraints(TargetSize::from_bytes(10 * MB), VideoCodec::HEVC),
+    )
+    .await;
+    assert_eq!(converted.result().job.height(), Some(1440));
+}
+
+#[tokio::test]
+async fn oversized_video_gets_another_pass_2_at_a_lower_bitrate() {
+    let fake = Arc::new(fake().with_output_sizes([12 * MB, 1000]));
+    let converted = convert(
+        fake.clone(),
+        "test.avi",
+        &constraints(TargetSize::from_bytes(10 * MB), VideoCodec::WEBM),
+    )
+    .await;
+
+    let result = converted.result();
+    assert_eq!(result.status, JobStatus::Finished);
+    assert_eq!(result.job.size_attempts(), 2);
+    let runs = fake.runs();
+    assert_eq!(runs.len(), 3);
+    assert!(runs[2].windows(2).any(|a| a == ["-pass", "2"]));
+    // 20% over, so at least that much lower
+    assert!(video_bitrate(&runs[2]) as f32 <= video_bitrate(&runs[1]) as f32 / 1.2);
+}
+
+#[tokio::test]
+async fn video_that_never_fits_fails_after_the_last_attempt() {
+    let fake = Arc::new(fake().with_output_sizes([12 * MB]));
+    let converted = convert(
+        fake.clone(),
+        "test.avi",
+        &constraints(TargetSize::from_bytes(10 * MB), VideoCodec::WEBM),
+    )
+    .await;
+
+    let JobStatus::Failed(reason) = &converted.result().status else {
+        panic!("{:?}", converted.result().status);
+    };
+    assert!(reason.contains("after 3 attempts"), "{reason}");
+    assert_eq!(fake.runs().len(), 4);
+    assert!(converted.files().is_empty(), "{:?}", converted.files());
+}
+
+#[tokio::test]
+async fn image_searches_for_the_highest_quality_that_fits() {
+    let fake = Arc::new(fake_jpeg_xl().with_output_sizes([2 * MB, 2 * MB, 500_000]));
+    let converted = convert(
+        fake.clone(),
+        "test.jpg",
+        &constraints(TargetSize::from_bytes(MB), VideoCodec::WEBM),
+    )
+    .await;
+
+    let result = converted.result();
+    assert_eq!(result.status, JobStatus::Finished);
+    let image = result.job.image_settings().unwrap();
+    assert!((10..90).contains(&image.quality), "{image:?}");
+    assert_eq!(image.resolution, Some((2560, 1440)));
+    assert!(fake.runs().len() > 3);
+    assert_eq!(converted.files(), ["minified_test.webp"]);
+    assert!(matches!(
+        converted.events.last(),
+        Some(Event::Finished { image: Some(_), .. })
+    ));
+}
+
+#[tokio::test]
+async fn still_images_are_encoded_in_process() {
+    let fake = Arc::new(fake());
+    let converted = convert(
+        fake.clone(),
+        "test.jpg",
+        &constraints(TargetSize::from_bytes(10 * MB), VideoCodec::WEBM),
+    )
+    .await;
+
+    let result = converted.result();
+    assert_eq!(result.status, JobStatus::Finished);
+    assert_eq!(result.job.codec(), "libwebp");
+    assert!(fake.runs().is_empty(), "{:?}", fake.runs());
+    assert_eq!(converted.files(), ["minified_test.webp"]);
+    let output = native::probe(result.job.output()).unwrap();
+    assert_eq!(output.video().unwrap().codec_name, "webp");
+    assert_eq!(output.video().unwrap().resolution(), Some((2560, 1440)));
+
+    let progress = converted
+        .events
+        .iter()
+        .filter_map(|e| match e {
+            Event::Progress {
+                out_time, duration, ..
+            } => Some((*out_time, *duration)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(progress.first(), Some(&(0., Some(1.))));
+    assert_eq!(progress.last(), Some(&(1., Some(1.))));
+}
+
+#[tokio::test]
+async fn still_image_search_lowers_the_quality_until_it_fits() {
+    let constraints = Constraints {
+        image_format: ImageFormat::JPEG,
+        ..constraints(TargetSize::from_bytes(200_000), VideoCodec::WEBM)
+    };
+    let converted = convert(Arc::new(fake()), "test.jpg", &constraints).await;
+
+    let result = converted.result();
+    assert_eq!(result.status, JobStatus::Finished);
+    assert!(
+        result.output_size.unwrap() <= 200_000,
+        "{:?}",
+        result.output_size
+    );
+    let image = result.job.image_settings().unwrap();
+    assert!(image.quality < 90, "{image:?}");
+    assert!(result.job.size_attempts() > 1);
+    assert_eq!(converted.files(), ["minified_test.jpg"]);
+}
+
+#[tokio::test]
+async fn failed_encode_keeps_the_error_and_removes_its_output() {
+    let fake = Arc::new(fake_jpeg_xl().failing(
+        1,
+        &[
+            "[libvpx-vp9 @ 0x0] Error: something broke",
+            "Conversion failed!",
+        ],
+    ));
+    let converted = convert(
+        fake,
+        "test.jpg",
+        &constraints(TargetSize::from_bytes(MB), VideoCodec::WEBM),
+    )
+    .await;
+
+    let result = converted.result();
+    assert_eq!(
+        result.status,
+        JobStatus::Failed("ffmpeg exit code 1: [libvpx-vp9 @ 0x0] Error: something broke".into())
+    );
+    assert_eq!(result.stderr_tail.len(), 2);
+    assert!(converted.files().is_empty(), "{:?}", converted.files());
+    assert!(matches!(
+        converted.events.last(),
+        Some(Event::Failed { .. })
+    ));
+}
+
+#[tokio::test]
+async fn cancelled_job_removes_what_it_wrote() {
+    let (dir, mut run) = start(
+        Arc::new(fake_jpeg_xl().hanging()),
+        "test.jpg",
+        &constraints(TargetSize::from_bytes(MB), VideoCodec::WEBM),
+    )
+    .await;
+    while let Some(event) = run.next_event().await {
+        if matches!(event, Event::PassStarted { .. }) {
+            break;
+        }
+    }
+    // the partial output is there while the encode runs
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    run.cancel();
+    let mut events = vec![];
+    while let Some(event) = run.next_event().await {
+        events.push(event);
+    }
+    let results = run.results().await.unwrap();
+    assert_eq!(results[0].status, JobStatus::Cancelled);
+    assert!(matches!(events.last(), Some(Event::Cancelled { job: 0 })));
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn jobs_ffmpeg_has_no_encoder_for_are_refused() {
+    let fake = fake();
+    let constraints = constraints(TargetSize::from_bytes(MB), VideoCodec::WEBM);
+    let (_avi_dir, avi) = plan(&fake, "test.avi", &constraints).await;
+    let (_jpg_dir, jpg) = plan(&fake_jpeg_xl(), "test.jpg", &constraints).await;
+    let capabilities = Capabilities::parse(
+        "ffmpeg version 6.1.1",
+        " ------\n V....D libvpx-vp9 x\n A....D libopus x\n",
+        " ..C scale V->V x\n ..C fps V->V x\n T.. select V->N x\n",
+    );
+
+    assert!(capabilities.check_jobs(&[avi]).is_ok());
+    assert_eq!(
+        capabilities.check_jobs(&[jpg]).unwrap_err().to_string(),
+        "ffmpeg can't do every job: no libwebp encoder (for test.jpg)"
+    );
+}
+
+#[tokio::test]
+async fn audio_needs_no_video_encoder() {
+    // test.mkv without its video
+    let mut audio = fixture_probe("test.mkv");
+    audio.streams.remove(0);
+    let audio_only = fake().with_probe(fixture("test.mkv"), audio);
+    let mut constraints = constraints(TargetSize::from_bytes(MB), VideoCodec::WEBM);
+    let capabilities = Capabilities::parse(
+        "ffmpeg version 6.1.1",
+        " ------\n A....D libopus x\n",
+        " ..C scale V->V x\n ..C fps V->V x\n T.. select V->N x\n",
+    );
+
+    assert_eq!(capabilities.check(&mut constraints).unwrap(), None);
+    let (_dir, job) = plan(&audio_only, "test.mkv", &constraints).await;
+    assert_eq!(job.media_type(), MediaType::Audio);
+    assert!(capabilities.check_jobs(&[job]).is_ok());
+
+    let (_dir, video) = plan(&fake(), "test.mkv", &constraints).await;
+    assert_eq!(
+        capabilities.check_jobs(&[video]).unwrap_err().to_string(),
+        "ffmpeg has no libvpx-vp9 encoder for WEBM, nor any other video encoder nmb uses"
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs ffmpeg and ffprobe installed, run with --ignored"]
+async fn system_ffmpeg_converts_every_fixture() {
+    let ffmpeg = system();
+    let mut h264 = constraints(TargetSize::from_bytes(MB), VideoCodec::H264);
+    ffmpeg
+        .capabilities()
+        .await
+        .unwrap()
+        .check(&mut h264)
+        .unwrap();
+    let ffmpeg = Arc::new(ffmpeg);
+    for (name, media_type) in [
+        ("test.avi", MediaType::Video),
+        ("test.mkv", MediaType::Video),
+        ("test.jpg", MediaType::Image),
+    ] {
+        let path = fixture(name);
+        let probe = ffmpeg.probe(&path).await.unwrap();
+        assert_eq!(
+            probe.media_type(media_type_hint(&path)).unwrap(),
+            media_type,
+            "{name}"
+        );
+
+        let converted = convert(ffmpeg.clone(), name, &h264).await;
+        let result = converted.result();
+        assert_eq!(result.status, JobStatus::Finished, "{name}");
+        assert!(result.output_size.unwrap() <= h264.size.bytes(), "{name}");
+        assert_eq!(
+            converted.files().len(),
+            1,
+            "{name}: {:?}",
+            converted.files()
+        );
+    }
+}